@@ -0,0 +1,235 @@
+use rand::Rng;
+
+use crate::{
+    data::{Churn, OfflinePeriod},
+    stream::ReceivedMessage,
+    utils::{now_millis, sample_prob},
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Transition {
+    Offline,
+    Online,
+}
+
+/// Decides when a client goes offline and records the periods it spent offline.
+pub struct ChurnSchedule {
+    churn: Churn,
+    offline: bool,
+    periods: Vec<OfflinePeriod>,
+}
+
+impl ChurnSchedule {
+    pub fn new(churn: Churn) -> Self {
+        Self {
+            churn,
+            offline: false,
+            periods: Vec::new(),
+        }
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Moves the schedule to `tick`, returning whether the client has to go offline or come back.
+    pub fn next<R: Rng>(&mut self, tick: u32, rng: &mut R) -> Option<Transition> {
+        let offline = match &self.churn {
+            Churn::Windows { windows } => windows
+                .iter()
+                .any(|window| window.start_tick <= tick && tick < window.end_tick),
+            Churn::Random {
+                mean_online_ticks,
+                mean_offline_ticks,
+            } => {
+                let mean_ticks = if self.offline {
+                    mean_offline_ticks
+                } else {
+                    mean_online_ticks
+                };
+                // leaving a state with probability 1/mean gives geometric period lengths
+                self.offline ^ sample_prob((1.0 / mean_ticks.max(1.0)) as f32, rng)
+            }
+        };
+        if offline == self.offline {
+            return None;
+        }
+        self.offline = offline;
+        if offline {
+            self.periods.push(OfflinePeriod {
+                start_tick: tick,
+                end_tick: None,
+                offline_since: now_millis(),
+                online_at: None,
+                queued_messages: 0,
+                max_latency_millis: None,
+                mean_latency_millis: None,
+            });
+            Some(Transition::Offline)
+        } else {
+            if let Some(period) = self.periods.last_mut() {
+                period.end_tick = Some(tick);
+                period.online_at = Some(now_millis());
+            }
+            Some(Transition::Online)
+        }
+    }
+
+    pub fn finish(self) -> Vec<OfflinePeriod> {
+        self.periods
+    }
+}
+
+/// Fills in the messages that were sent to the client while it was offline and how late they arrived.
+pub fn queued_summary(periods: &mut [OfflinePeriod], received: &[ReceivedMessage]) {
+    for period in periods {
+        let queued: Vec<u128> = received
+            .iter()
+            .filter(|msg| {
+                msg.send_time.is_some_and(|sent| {
+                    sent >= period.offline_since && period.online_at.is_none_or(|at| sent < at)
+                })
+            })
+            .filter_map(|msg| msg.latency_millis)
+            .collect();
+        period.queued_messages = queued.len() as u32;
+        period.max_latency_millis = queued.iter().max().copied();
+        period.mean_latency_millis =
+            (!queued.is_empty()).then(|| queued.iter().sum::<u128>() / queued.len() as u128);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    use super::*;
+    use crate::data::{MessageType, OfflineWindow};
+
+    fn windows(windows: &[(u32, u32)]) -> ChurnSchedule {
+        ChurnSchedule::new(Churn::Windows {
+            windows: windows
+                .iter()
+                .map(|&(start_tick, end_tick)| OfflineWindow {
+                    start_tick,
+                    end_tick,
+                })
+                .collect(),
+        })
+    }
+
+    fn transitions(schedule: &mut ChurnSchedule, ticks: u32) -> Vec<(u32, Transition)> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..ticks)
+            .filter_map(|tick| schedule.next(tick, &mut rng).map(|t| (tick, t)))
+            .collect()
+    }
+
+    #[test]
+    fn window_transitions() {
+        let mut schedule = windows(&[(2, 4), (6, 7)]);
+        assert_eq!(
+            transitions(&mut schedule, 10),
+            vec![
+                (2, Transition::Offline),
+                (4, Transition::Online),
+                (6, Transition::Offline),
+                (7, Transition::Online),
+            ]
+        );
+        assert!(!schedule.is_offline());
+
+        let periods = schedule.finish();
+        assert_eq!(periods.len(), 2);
+        assert_eq!((periods[0].start_tick, periods[0].end_tick), (2, Some(4)));
+        assert_eq!((periods[1].start_tick, periods[1].end_tick), (6, Some(7)));
+        assert!(periods.iter().all(|p| p.online_at.is_some()));
+    }
+
+    #[test]
+    fn adjacent_windows_are_one_period() {
+        let mut schedule = windows(&[(2, 4), (4, 6), (5, 8)]);
+        assert_eq!(
+            transitions(&mut schedule, 10),
+            vec![(2, Transition::Offline), (8, Transition::Online)]
+        );
+    }
+
+    #[test]
+    fn empty_window() {
+        let mut schedule = windows(&[(3, 3)]);
+        assert!(transitions(&mut schedule, 10).is_empty());
+        assert!(schedule.finish().is_empty());
+    }
+
+    #[test]
+    fn offline_at_the_end() {
+        let mut schedule = windows(&[(5, 20)]);
+        assert_eq!(
+            transitions(&mut schedule, 10),
+            vec![(5, Transition::Offline)]
+        );
+        assert!(schedule.is_offline());
+
+        let periods = schedule.finish();
+        assert_eq!(periods[0].end_tick, None);
+        assert_eq!(periods[0].online_at, None);
+    }
+
+    #[test]
+    fn random_transitions_alternate() {
+        let mut schedule = ChurnSchedule::new(Churn::Random {
+            mean_online_ticks: 20.0,
+            mean_offline_ticks: 5.0,
+        });
+        let transitions = transitions(&mut schedule, 10_000);
+        assert!(!transitions.is_empty());
+        for (i, (_, transition)) in transitions.iter().enumerate() {
+            let expected = if i % 2 == 0 {
+                Transition::Offline
+            } else {
+                Transition::Online
+            };
+            assert_eq!(*transition, expected);
+        }
+
+        // offline for about a fifth of the run
+        let offline: u32 = schedule
+            .finish()
+            .iter()
+            .map(|period| period.end_tick.unwrap_or(10_000) - period.start_tick)
+            .sum();
+        assert!(
+            (1_500..2_500).contains(&offline),
+            "offline for {offline} ticks"
+        );
+    }
+
+    #[test]
+    fn queued_messages() {
+        let mut schedule = windows(&[(1, 2)]);
+        transitions(&mut schedule, 3);
+        let mut periods = schedule.finish();
+        periods[0].offline_since = 1_000;
+        periods[0].online_at = Some(2_000);
+
+        let message = |send_time, latency_millis| ReceivedMessage {
+            from: "bob".to_string(),
+            r#type: MessageType::Regular,
+            tick: 0,
+            send_time: Some(send_time),
+            latency_millis: Some(latency_millis),
+        };
+        let received = [
+            message(999, 10),
+            message(1_000, 1_500),
+            message(1_500, 700),
+            message(2_000, 10),
+        ];
+        queued_summary(&mut periods, &received);
+
+        assert_eq!(periods[0].queued_messages, 2);
+        assert_eq!(periods[0].max_latency_millis, Some(1_500));
+        assert_eq!(periods[0].mean_latency_millis, Some(1_100));
+    }
+}
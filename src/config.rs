@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -9,6 +11,58 @@ pub struct DenimClientConfig {
 
     pub channel_buffer_size: Option<usize>,
     pub inmemory: bool,
+    /// Number of clients simulated by this process, defaults to one
+    pub client_count: Option<usize>,
+    /// Stream message logs to the dispatcher every `log_flush_ticks` ticks
+    pub log_flush_ticks: Option<u32>,
+    /// Directory reports are written to before they are uploaded, defaults to `spool`
+    pub spool_directory: Option<String>,
+    /// Route traffic to the server through a local proxy injecting these faults
+    pub fault_profile: Option<FaultProfile>,
+    /// Where SQLite stores are kept when `inmemory` is false, defaults to the working directory
+    pub storage: Option<StorageConfig>,
 
     pub logging: Option<String>,
 }
+
+/// Faults injected by the proxy into every chunk of data it forwards.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct FaultProfile {
+    pub latency_millis: Option<u32>,
+    /// Random extra latency of up to `jitter_millis`
+    pub jitter_millis: Option<u32>,
+    /// Probability of delaying a chunk by a further `spike_millis`
+    pub spike_probability: Option<f32>,
+    pub spike_millis: Option<u32>,
+    pub bandwidth_bytes_per_second: Option<u64>,
+    /// Probability of closing the connection instead of forwarding a chunk
+    pub reset_probability: Option<f32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageConfig {
+    pub root: Option<String>,
+    /// Keep each client's stores in a subdirectory named after the client
+    #[serde(default)]
+    pub per_client: bool,
+    /// Pragmas applied to the store files before the clients open them, e.g. `journal_mode`
+    #[serde(default)]
+    pub pragmas: BTreeMap<String, String>,
+    #[serde(default)]
+    pub after_run: StoreLifecycle,
+}
+
+/// What happens to the store files once a client has finished its run.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum StoreLifecycle {
+    #[default]
+    Keep,
+    /// Move the files to `{directory}/{username}-{start_time}`
+    Archive {
+        directory: String,
+    },
+    Delete,
+}
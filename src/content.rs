@@ -0,0 +1,209 @@
+use derive_more::{Display, Error, From};
+use rand::Rng;
+
+use crate::data::PayloadContent;
+
+const LOREM: &[&str] = &[
+    "lorem",
+    "ipsum",
+    "dolor",
+    "sit",
+    "amet",
+    "consectetur",
+    "adipiscing",
+    "elit",
+    "sed",
+    "do",
+    "eiusmod",
+    "tempor",
+    "incididunt",
+    "ut",
+    "labore",
+    "et",
+    "dolore",
+    "magna",
+    "aliqua",
+    "enim",
+    "ad",
+    "minim",
+    "veniam",
+    "quis",
+    "nostrud",
+    "exercitation",
+    "ullamco",
+    "laboris",
+    "nisi",
+    "aliquip",
+    "ex",
+    "ea",
+    "commodo",
+    "consequat",
+];
+
+#[derive(Debug, Display, Error, From)]
+pub enum ContentError {
+    Io(std::io::Error),
+    /// The corpus or pattern has nothing to fill a body with
+    Empty,
+}
+
+enum Content {
+    RandomBytes,
+    Lorem,
+    Corpus(Vec<String>),
+    Pattern(Vec<u8>),
+}
+
+/// Fills message bodies with the configured kind of content.
+pub struct ContentGenerator {
+    content: Content,
+}
+
+impl ContentGenerator {
+    pub fn new(content: &PayloadContent) -> Result<Self, ContentError> {
+        let content = match content {
+            PayloadContent::RandomBytes => Content::RandomBytes,
+            PayloadContent::Lorem => Content::Lorem,
+            PayloadContent::Corpus { path } => {
+                let lines: Vec<String> = std::fs::read_to_string(path)?
+                    .lines()
+                    .filter(|line| !line.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                if lines.is_empty() {
+                    return Err(ContentError::Empty);
+                }
+                Content::Corpus(lines)
+            }
+            PayloadContent::Pattern { pattern } => {
+                if pattern.is_empty() {
+                    return Err(ContentError::Empty);
+                }
+                Content::Pattern(pattern.as_bytes().to_vec())
+            }
+        };
+        Ok(Self { content })
+    }
+
+    pub fn body<R: Rng>(&self, length: usize, rng: &mut R) -> Vec<u8> {
+        match &self.content {
+            Content::RandomBytes => (0..length).map(|_| rng.r#gen()).collect(),
+            Content::Lorem => fill_text(length, ' ', || LOREM[rng.gen_range(0..LOREM.len())]),
+            Content::Corpus(lines) => {
+                fill_text(length, '\n', || &lines[rng.gen_range(0..lines.len())])
+            }
+            Content::Pattern(pattern) => pattern.iter().copied().cycle().take(length).collect(),
+        }
+    }
+}
+
+/// Joins pieces of text until `length` bytes are filled, without splitting a character.
+fn fill_text<'a>(length: usize, separator: char, mut next: impl FnMut() -> &'a str) -> Vec<u8> {
+    let mut text = String::with_capacity(length);
+    while text.len() < length {
+        if !text.is_empty() {
+            text.push(separator);
+        }
+        text.push_str(next());
+    }
+    let mut end = length;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    // a character cut off at the end is replaced by spaces, so the body keeps its length
+    let mut bytes = text.into_bytes();
+    bytes.resize(length, b' ');
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    fn pieces<'a>(pieces: &'a [&'a str]) -> impl FnMut() -> &'a str {
+        let mut next = pieces.iter().cycle();
+        move || next.next().unwrap()
+    }
+
+    #[test]
+    fn fill_text_has_exact_length() {
+        for length in 0..40 {
+            let text = fill_text(length, ' ', pieces(&["lorem", "ipsum"]));
+            assert_eq!(text.len(), length);
+            assert!(
+                "lorem ipsum lorem ipsum lorem ipsum lorem"
+                    .starts_with(std::str::from_utf8(&text).unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn fill_text_keeps_characters_whole() {
+        // "é" takes two bytes and "日本" six
+        let full = "é\n日本\né\n日本\né\n日本";
+        for length in 0..full.len() {
+            let text = fill_text(length, '\n', pieces(&["é", "日本"]));
+            assert_eq!(text.len(), length);
+            let text = String::from_utf8(text).unwrap();
+            let kept = text.trim_end_matches(' ');
+            assert!(full.starts_with(kept), "{text:?}");
+            // only a character that did not fit is padded
+            assert!(text.len() - kept.len() < 3, "{text:?}");
+        }
+        assert_eq!(fill_text(4, '\n', pieces(&["日本"])), "日 ".as_bytes());
+    }
+
+    #[test]
+    fn bodies_have_the_requested_length() {
+        let path = std::env::temp_dir().join(format!("corpus-{}.txt", std::process::id()));
+        std::fs::write(&path, "first line\n\nzweite Zeile ü\n").unwrap();
+        let contents = [
+            PayloadContent::RandomBytes,
+            PayloadContent::Lorem,
+            PayloadContent::Corpus {
+                path: path.display().to_string(),
+            },
+            PayloadContent::Pattern {
+                pattern: "ab".to_string(),
+            },
+        ];
+        let generators: Vec<ContentGenerator> = contents
+            .iter()
+            .map(|content| ContentGenerator::new(content).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut rng = StdRng::seed_from_u64(7);
+        for generator in &generators {
+            for length in [0, 1, 15, 100] {
+                assert_eq!(generator.body(length, &mut rng).len(), length);
+            }
+        }
+        assert_eq!(generators[3].body(5, &mut rng), b"ababa");
+        let corpus = String::from_utf8(generators[2].body(200, &mut rng)).unwrap();
+        assert!(corpus.split('\n').all(|line| !line.is_empty()));
+    }
+
+    #[test]
+    fn empty_content_is_rejected() {
+        let pattern = PayloadContent::Pattern {
+            pattern: String::new(),
+        };
+        assert!(matches!(
+            ContentGenerator::new(&pattern),
+            Err(ContentError::Empty)
+        ));
+
+        let path = std::env::temp_dir().join(format!("empty-corpus-{}.txt", std::process::id()));
+        std::fs::write(&path, "\n\n").unwrap();
+        let corpus = PayloadContent::Corpus {
+            path: path.display().to_string(),
+        };
+        let result = ContentGenerator::new(&corpus);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(ContentError::Empty)));
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use crate::{
+    data::{ConversationConfig, ConversationLog, Friend, MessageType},
+    stream::ReceivedMessage,
+    utils::{get_friend, sample_prob},
+};
+
+#[derive(Clone, Debug)]
+pub struct Recipient {
+    pub friend: String,
+    pub msg_type: MessageType,
+}
+
+impl Recipient {
+    /// Picks a weighted random friend, from `denim_friends` with probability `denim_prob`.
+    pub fn pick<R: Rng>(
+        friends: &HashMap<String, Friend>,
+        denim_friends: &HashMap<String, Friend>,
+        denim_prob: f32,
+        rng: &mut R,
+    ) -> Option<Self> {
+        let denim = sample_prob(denim_prob, rng) && !denim_friends.is_empty();
+        let (friends, msg_type) = if denim {
+            (denim_friends, MessageType::Denim)
+        } else {
+            (friends, MessageType::Regular)
+        };
+        get_friend(friends, rng).map(|friend| Self {
+            friend: friend.username,
+            msg_type,
+        })
+    }
+}
+
+struct Conversation {
+    recipient: Recipient,
+    start_tick: u32,
+    length: u32,
+    sent: u32,
+    next_tick: u32,
+}
+
+/// Keeps track of the conversation a client is currently in and the ones it has closed.
+pub struct Conversations {
+    config: ConversationConfig,
+    open: Option<Conversation>,
+    closed: Vec<ConversationLog>,
+}
+
+impl Conversations {
+    pub fn new(config: ConversationConfig) -> Self {
+        Self {
+            config,
+            open: None,
+            closed: Vec::new(),
+        }
+    }
+
+    /// Recipient of the conversation message due in `tick`, if any. When no conversation is
+    /// open and the send process wants to send, a new one is opened with the recipient from `open`.
+    pub fn next<R: Rng>(
+        &mut self,
+        tick: u32,
+        sends: u32,
+        open: impl FnOnce(&mut R) -> Option<Recipient>,
+        rng: &mut R,
+    ) -> Option<Recipient> {
+        if self.open.is_none() && sends > 0 {
+            let (min, max) = self.config.message_range;
+            self.open = Some(Conversation {
+                recipient: open(rng)?,
+                start_tick: tick,
+                length: rng.gen_range(min..=max).max(1),
+                sent: 0,
+                next_tick: tick,
+            });
+        }
+        let conversation = self.open.as_mut()?;
+        if tick < conversation.next_tick {
+            return None;
+        }
+        let (min, max) = self.config.think_ticks_range;
+        conversation.sent += 1;
+        conversation.next_tick = tick + rng.gen_range(min..=max).max(1);
+        let recipient = conversation.recipient.clone();
+        if conversation.sent >= conversation.length {
+            self.close(tick, true);
+        }
+        Some(recipient)
+    }
+
+    /// Closes the open conversation as unfinished and returns all conversations.
+    pub fn finish(mut self, tick: u32) -> Vec<ConversationLog> {
+        self.close(tick, false);
+        self.closed
+    }
+
+    fn close(&mut self, tick: u32, completed: bool) {
+        if let Some(conversation) = self.open.take() {
+            self.closed.push(ConversationLog {
+                friend: conversation.recipient.friend,
+                r#type: conversation.recipient.msg_type,
+                start_tick: conversation.start_tick,
+                end_tick: tick,
+                length: conversation.length,
+                sent: conversation.sent,
+                received: 0,
+                completed,
+            });
+        }
+    }
+}
+
+/// Fills in how many messages each conversation partner sent while the conversation was open.
+pub fn count_received(conversations: &mut [ConversationLog], received: &[ReceivedMessage]) {
+    for conversation in conversations {
+        conversation.received = received
+            .iter()
+            .filter(|msg| {
+                msg.from == conversation.friend
+                    && msg.r#type == conversation.r#type
+                    && (conversation.start_tick..=conversation.end_tick).contains(&msg.tick)
+            })
+            .count() as u32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    fn recipient(friend: &str) -> Recipient {
+        Recipient {
+            friend: friend.to_string(),
+            msg_type: MessageType::Regular,
+        }
+    }
+
+    fn conversations(message_range: (u32, u32), think_ticks_range: (u32, u32)) -> Conversations {
+        Conversations::new(ConversationConfig {
+            message_range,
+            think_ticks_range,
+        })
+    }
+
+    #[test]
+    fn lengths_and_think_times_stay_in_range() {
+        let mut conversations = conversations((2, 4), (3, 5));
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut opened = 0;
+        let mut sends = Vec::new();
+        for tick in 0..1000 {
+            let open = |_: &mut StdRng| {
+                opened += 1;
+                Some(recipient(&format!("friend{opened}")))
+            };
+            if let Some(recipient) = conversations.next(tick, 1, open, &mut rng) {
+                sends.push((tick, recipient.friend));
+            }
+        }
+        let logs = conversations.finish(1000);
+        assert_eq!(logs.len(), opened);
+
+        for log in logs.iter().filter(|log| log.completed) {
+            let ticks: Vec<u32> = sends
+                .iter()
+                .filter(|(_, friend)| *friend == log.friend)
+                .map(|(tick, _)| *tick)
+                .collect();
+            assert!((2..=4).contains(&log.length), "{log:?}");
+            assert_eq!(log.sent, log.length);
+            assert_eq!(ticks.len() as u32, log.length);
+            assert_eq!(ticks.first(), Some(&log.start_tick));
+            assert_eq!(ticks.last(), Some(&log.end_tick));
+            for pair in ticks.windows(2) {
+                assert!((3..=5).contains(&(pair[1] - pair[0])), "{ticks:?}");
+            }
+        }
+        // every length in the range shows up
+        for length in 2..=4 {
+            assert!(logs.iter().any(|log| log.length == length));
+        }
+    }
+
+    #[test]
+    fn zero_ranges_send_one_message_per_tick() {
+        let mut conversations = conversations((0, 0), (0, 0));
+        let mut rng = StdRng::seed_from_u64(7);
+        for tick in 0..3 {
+            let next = conversations.next(tick, 1, |_| Some(recipient("bob")), &mut rng);
+            assert!(next.is_some());
+        }
+        let logs = conversations.finish(3);
+        assert_eq!(logs.len(), 3);
+        assert!(
+            logs.iter()
+                .all(|log| log.length == 1 && log.sent == 1 && log.completed)
+        );
+    }
+
+    #[test]
+    fn opens_only_when_sending() {
+        let mut conversations = conversations((2, 2), (1, 1));
+        let mut rng = StdRng::seed_from_u64(7);
+        let open = |_: &mut StdRng| -> Option<Recipient> { panic!("opened without a send") };
+        assert!(conversations.next(0, 0, open, &mut rng).is_none());
+        assert!(conversations.next(1, 1, |_| None, &mut rng).is_none());
+        assert!(conversations.finish(2).is_empty());
+    }
+
+    #[test]
+    fn open_conversation_continues_without_sends() {
+        let mut conversations = conversations((2, 2), (1, 1));
+        let mut rng = StdRng::seed_from_u64(7);
+        assert!(
+            conversations
+                .next(0, 1, |_| Some(recipient("bob")), &mut rng)
+                .is_some()
+        );
+        let next = conversations.next(1, 0, |_| Some(recipient("carol")), &mut rng);
+        assert_eq!(
+            next.map(|recipient| recipient.friend).as_deref(),
+            Some("bob")
+        );
+
+        let logs = conversations.finish(2);
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].completed);
+        assert_eq!((logs[0].start_tick, logs[0].end_tick), (0, 1));
+    }
+
+    #[test]
+    fn finish_closes_the_open_conversation() {
+        let mut conversations = conversations((5, 5), (2, 2));
+        let mut rng = StdRng::seed_from_u64(7);
+        for tick in 0..4 {
+            conversations.next(tick, 1, |_| Some(recipient("bob")), &mut rng);
+        }
+        let logs = conversations.finish(4);
+        assert_eq!(logs.len(), 1);
+        assert!(!logs[0].completed);
+        assert_eq!(logs[0].friend, "bob");
+        assert_eq!((logs[0].length, logs[0].sent), (5, 2));
+        assert_eq!((logs[0].start_tick, logs[0].end_tick), (0, 4));
+    }
+
+    #[test]
+    fn received_messages_are_counted_per_conversation() {
+        let mut conversations = conversations((5, 5), (1, 1));
+        let mut rng = StdRng::seed_from_u64(7);
+        conversations.next(0, 1, |_| Some(recipient("bob")), &mut rng);
+        let mut logs = conversations.finish(5);
+
+        let received = |from: &str, r#type: MessageType, tick: u32| ReceivedMessage {
+            from: from.to_string(),
+            r#type,
+            tick,
+            send_time: None,
+            latency_millis: None,
+        };
+        let messages = [
+            received("bob", MessageType::Regular, 3),
+            received("bob", MessageType::Regular, 5),
+            received("bob", MessageType::Regular, 6),
+            received("bob", MessageType::Denim, 3),
+            received("carol", MessageType::Regular, 3),
+        ];
+        count_received(&mut logs, &messages);
+        assert_eq!(logs[0].received, 2);
+    }
+}
@@ -2,6 +2,8 @@ use sam_common::AccountId;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use crate::payload::Integrity;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Friend {
     pub username: String,
@@ -9,12 +11,23 @@ pub struct Friend {
     pub denim: bool,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Group {
+    pub members: Vec<String>,
+    pub frequency: f64,
+    pub denim: bool,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ClientInfo {
     pub client_type: ClientType,
     pub username: String,
     pub message_size_range: (u32, u32),
+    #[serde(default)]
+    pub message_size_distribution: SizeDistribution,
+    #[serde(default)]
+    pub payload_content: PayloadContent,
     pub send_rate: u32,
     pub reply_rate: u32,
     pub tick_millis: u32,
@@ -23,6 +36,162 @@ pub struct ClientInfo {
     pub reply_probability: f32,
     pub stale_reply: u32,
     pub friends: HashMap<String, Friend>,
+    #[serde(default)]
+    pub groups: HashMap<String, Group>,
+    /// Send a message to a group every `group_send_rate` ticks, groups are picked by frequency.
+    /// A rate of 0 sends no group messages
+    pub group_send_rate: Option<u32>,
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub missed_tick_policy: MissedTickPolicy,
+    pub max_phase_offset_millis: Option<u32>,
+    pub max_jitter_millis: Option<u32>,
+    #[serde(default)]
+    pub send_process: SendProcess,
+    #[serde(default)]
+    pub traffic_profile: Vec<ProfileSegment>,
+    pub conversation: Option<ConversationConfig>,
+    pub churn: Option<Churn>,
+    /// Ticks at which the client is dropped without disconnecting and recreated from its stores
+    #[serde(default)]
+    pub restart_ticks: Vec<u32>,
+}
+
+/// When the client disconnects from the server during the run.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Churn {
+    /// Offline during each of the given windows
+    Windows { windows: Vec<OfflineWindow> },
+    /// Alternates between online and offline periods of geometrically distributed length
+    #[serde(rename_all = "camelCase")]
+    Random {
+        mean_online_ticks: f64,
+        mean_offline_ticks: f64,
+    },
+}
+
+/// Offline between `start_tick` (inclusive) and `end_tick` (exclusive).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OfflineWindow {
+    pub start_tick: u32,
+    pub end_tick: u32,
+}
+
+/// How message sizes are drawn, samples are clamped to `message_size_range`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SizeDistribution {
+    /// Every size in `message_size_range` is equally likely
+    #[default]
+    Uniform,
+    #[serde(rename_all = "camelCase")]
+    Normal {
+        mean: f64,
+        std_dev: f64,
+    },
+    /// Sizes whose logarithm is normally distributed with mean `mu` and deviation `sigma`
+    LogNormal {
+        mu: f64,
+        sigma: f64,
+    },
+    Pareto {
+        scale: f64,
+        shape: f64,
+    },
+    /// Sizes drawn from a histogram, a JSON list of `{ "size", "weight" }` bins
+    Empirical {
+        path: String,
+    },
+}
+
+/// What message bodies following the payload header are filled with.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PayloadContent {
+    #[default]
+    RandomBytes,
+    /// Lorem ipsum style words separated by spaces
+    Lorem,
+    /// Random lines of a UTF-8 text file
+    Corpus { path: String },
+    /// `pattern` repeated over the whole body
+    Pattern { pattern: String },
+}
+
+/// Makes the client send in conversations with one friend at a time instead of picking
+/// a friend for every message.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationConfig {
+    /// Inclusive range of messages sent in one conversation
+    pub message_range: (u32, u32),
+    /// Inclusive range of ticks between two messages of a conversation
+    pub think_ticks_range: (u32, u32),
+}
+
+/// Scales the client's traffic between `start_tick` (inclusive) and `end_tick` (exclusive).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileSegment {
+    pub start_tick: u32,
+    pub end_tick: u32,
+    pub load: LoadShape,
+    pub denim_probability: Option<f32>,
+}
+
+/// Load relative to the configured rates, 1.0 being the configured rates themselves.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum LoadShape {
+    Constant {
+        load: f64,
+    },
+    /// Linear change from `from` at the start to `to` at the end of the segment
+    Ramp {
+        from: f64,
+        to: f64,
+    },
+    /// Oscillates between `min` and `max`, starting at `min`
+    #[serde(rename_all = "camelCase")]
+    Sine {
+        min: f64,
+        max: f64,
+        period_ticks: u32,
+    },
+}
+
+/// How the number of messages sent per tick is chosen.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SendProcess {
+    /// One message every `send_rate` ticks
+    #[default]
+    Fixed,
+    /// A Poisson distributed number of messages per tick with mean `rate`
+    Poisson { rate: f64 },
+    /// Alternates between sending like `Poisson` and staying silent, with geometrically
+    /// distributed period lengths
+    #[serde(rename_all = "camelCase")]
+    OnOff {
+        rate: f64,
+        mean_on_ticks: f64,
+        mean_off_ticks: f64,
+    },
+}
+
+/// What the scheduler does with ticks it could not run on time.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MissedTickPolicy {
+    /// Run missed ticks back to back until the schedule is caught up
+    #[default]
+    Burst,
+    /// Drop missed ticks and continue with the next scheduled one
+    Skip,
+    /// Run the missed tick now and shift the schedule by the delay
+    Delay,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -34,7 +203,7 @@ pub enum ClientType {
     Other,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[serde(rename_all = "lowercase")]
 pub enum MessageType {
     Denim,
@@ -46,21 +215,229 @@ pub enum MessageType {
 #[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct MessageLog {
+    pub id: Option<String>,
+    pub reply_to: Option<String>,
+    pub group: Option<String>,
+    pub sequence: Option<u64>,
     #[serde(rename = "type")]
     pub r#type: MessageType,
     pub from: String,
     pub to: String,
     pub size: usize,
     pub tick: u32,
+    pub send_time: Option<u128>,
+    pub receive_time: Option<u128>,
+    pub latency_millis: Option<u128>,
+    pub integrity: Option<Integrity>,
 }
 
 #[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ClientReport {
     pub start_time: u128,
+    pub seed: u64,
+    pub phase_offset_millis: u64,
+    /// Set when the run was interrupted before `duration_ticks`
+    pub partial: bool,
+    pub interrupted_tick: Option<u32>,
+    pub tick_lateness: Vec<TickLateness>,
+    pub messages: Vec<MessageLog>,
+    /// Number of log batches streamed during the run, `messages` is empty when set
+    pub batches: Option<u64>,
+    /// Streamed batches the dispatcher did not take during the run, uploaded with the report
+    #[serde(default)]
+    #[builder(default)]
+    pub unsent_batches: Vec<LogBatch>,
+    pub delivery: Vec<DeliverySummary>,
+    pub sent: Vec<SentSummary>,
+    pub integrity: Vec<ChannelIntegrity>,
+    pub conversations: Vec<ConversationLog>,
+    pub group_messages: Vec<GroupMessageLog>,
+    pub offline_periods: Vec<OfflinePeriod>,
+    pub reconnects: Vec<ReconnectEvent>,
+    pub faults: Vec<FaultLog>,
+    pub restarts: Vec<RestartLog>,
+}
+
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RestartLog {
+    pub tick: u32,
+    /// Time the restart started at, in milliseconds since the epoch
+    pub time: u128,
+    /// Time it took to recreate the client from its stores
+    pub recovery_millis: u128,
+    pub recovered: bool,
+    /// Sequence numbers missing below the highest one received, between the restart and
+    /// the next one, as far as the client itself can tell
+    pub gaps: u64,
+    /// Messages sent since the previous restart, or the start of the run, that never arrived,
+    /// including those sent after the last restart. Only known once the dispatcher reconciled
+    /// the reports of the senders
+    pub lost: Option<u64>,
+    /// Messages that arrived again in the part of the run following the restart
+    pub duplicates: u64,
+}
+
+/// A fault injected by the proxy between the client and the server.
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FaultLog {
+    pub tick: u32,
+    pub time: u128,
+    pub direction: Direction,
+    pub fault: Fault,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// From the client to the server
+    Upstream,
+    /// From the server to the client
+    Downstream,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Fault {
+    #[serde(rename_all = "camelCase")]
+    DelaySpike {
+        delay_millis: u32,
+    },
+    Reset,
+}
+
+/// A lost connection to the server and the attempt to get it back.
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconnectEvent {
+    pub tick: u32,
+    pub disconnected_at: u128,
+    /// Not set when every attempt failed
+    pub reconnected_at: Option<u128>,
+    pub attempts: u32,
+    pub duration_millis: u128,
+}
+
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OfflinePeriod {
+    pub start_tick: u32,
+    /// Not set when the run ended while offline
+    pub end_tick: Option<u32>,
+    pub offline_since: u128,
+    pub online_at: Option<u128>,
+    /// Messages sent to the client while it was offline, which arrived after it reconnected
+    pub queued_messages: u32,
+    pub max_latency_millis: Option<u128>,
+    pub mean_latency_millis: Option<u128>,
+}
+
+/// A message fanned out to every member of a group, each member got its own copy with the same id.
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupMessageLog {
+    pub id: String,
+    pub group: String,
+    #[serde(rename = "type")]
+    pub r#type: MessageType,
+    pub tick: u32,
+    pub members: Vec<GroupDelivery>,
+}
+
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupDelivery {
+    pub member: String,
+    pub sequence: Option<u64>,
+    pub sent: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationLog {
+    pub friend: String,
+    #[serde(rename = "type")]
+    pub r#type: MessageType,
+    pub start_tick: u32,
+    pub end_tick: u32,
+    /// Number of messages planned for the conversation
+    pub length: u32,
+    pub sent: u32,
+    /// Messages received from the friend while the conversation was open
+    pub received: u32,
+    /// Not set when the run ended before the conversation did
+    pub completed: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TickLateness {
+    pub tick: u32,
+    pub lateness_millis: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LogBatch {
+    pub sequence: u64,
     pub messages: Vec<MessageLog>,
 }
 
+/// Messages received from a friend on one channel.
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliverySummary {
+    pub friend: String,
+    #[serde(rename = "type")]
+    pub r#type: MessageType,
+    pub received: u64,
+    pub duplicates: u64,
+    pub reordered: u64,
+    /// Sequence numbers missing below the highest one received
+    pub gaps: Vec<SequenceGap>,
+    /// Messages the friend sent that never arrived, including those sent after the highest
+    /// sequence received. Only known once the dispatcher reconciled the reports of both clients
+    pub lost: Option<u64>,
+}
+
+/// Sequence numbers `start..end` that were never received.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SequenceGap {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Messages sent to a friend on one channel.
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SentSummary {
+    pub friend: String,
+    #[serde(rename = "type")]
+    pub r#type: MessageType,
+    pub sent: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelIntegrity {
+    #[serde(rename = "type")]
+    pub r#type: MessageType,
+    pub intact: u64,
+    pub truncated: Vec<IntegrityFailure>,
+    pub corrupted: Vec<IntegrityFailure>,
+}
+
+#[derive(Serialize, Deserialize, Clone, bon::Builder, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityFailure {
+    pub id: Option<String>,
+    pub from: String,
+    pub tick: u32,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct StartInfo {
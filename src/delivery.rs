@@ -0,0 +1,507 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::data::{ClientReport, DeliverySummary, MessageType, SentSummary, SequenceGap};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Delivery {
+    InOrder,
+    Reordered,
+    Duplicate,
+}
+
+#[derive(Default)]
+struct Stream {
+    sent: u64,
+    received: u64,
+    duplicates: u64,
+    reordered: u64,
+    highest: Option<u64>,
+    seen: BTreeSet<u64>,
+}
+
+impl Stream {
+    /// Walks the received sequence numbers rather than the range up to the highest one,
+    /// so a bogus sequence in a header cannot blow up the summary.
+    fn gaps(&self) -> Vec<SequenceGap> {
+        let mut gaps = Vec::new();
+        let mut next = 0;
+        for &sequence in &self.seen {
+            if sequence > next {
+                gaps.push(SequenceGap {
+                    start: next,
+                    end: sequence,
+                });
+            }
+            next = sequence.saturating_add(1);
+        }
+        gaps
+    }
+
+    /// Number of sequence numbers in `start..end` that were never received.
+    fn missing(&self, start: u64, end: u64) -> u64 {
+        if start >= end {
+            return 0;
+        }
+        (end - start) - self.seen.range(start..end).count() as u64
+    }
+}
+
+/// Highest sequence number and number of duplicates received per stream at one point of the run.
+#[derive(Clone, Default)]
+pub struct Checkpoint {
+    streams: HashMap<(String, MessageType), (Option<u64>, u64)>,
+}
+
+/// Tracks per friend and message type sequence numbers, both for messages sent to a friend
+/// and for messages received from them.
+#[derive(Default)]
+pub struct DeliveryTracker {
+    streams: HashMap<(String, MessageType), Stream>,
+}
+
+impl DeliveryTracker {
+    /// The sequence number the next message sent to `friend` should carry.
+    pub fn next_sequence(&self, friend: &str, msg_type: &MessageType) -> u64 {
+        self.streams
+            .get(&(friend.to_string(), msg_type.clone()))
+            .map(|stream| stream.sent)
+            .unwrap_or(0)
+    }
+
+    /// Marks the message carrying `next_sequence` as sent.
+    pub fn sent(&mut self, friend: &str, msg_type: &MessageType) {
+        self.stream(friend, msg_type).sent += 1;
+    }
+
+    pub fn received(&mut self, friend: &str, msg_type: &MessageType, sequence: u64) -> Delivery {
+        let stream = self.stream(friend, msg_type);
+        if !stream.seen.insert(sequence) {
+            stream.duplicates += 1;
+            return Delivery::Duplicate;
+        }
+        stream.received += 1;
+        match stream.highest {
+            Some(highest) if sequence < highest => {
+                stream.reordered += 1;
+                Delivery::Reordered
+            }
+            _ => {
+                stream.highest = Some(sequence);
+                Delivery::InOrder
+            }
+        }
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            streams: self
+                .streams
+                .iter()
+                .map(|(key, stream)| (key.clone(), (stream.highest, stream.duplicates)))
+                .collect(),
+        }
+    }
+
+    /// Messages that never arrived and messages that arrived twice between `from` and `to`,
+    /// or between `from` and now when `to` is not given. Only gaps below the highest sequence
+    /// received are seen here, `reconcile` accounts for the rest.
+    pub fn between(&self, from: &Checkpoint, to: Option<&Checkpoint>) -> (u64, u64) {
+        let now = self.checkpoint();
+        let to = to.unwrap_or(&now);
+        let mut lost = 0;
+        let mut duplicates = 0;
+        for (key, (highest, dups)) in &to.streams {
+            let (from_highest, from_dups) = from.streams.get(key).copied().unwrap_or_default();
+            duplicates += dups.saturating_sub(from_dups);
+            let (Some(highest), Some(stream)) = (highest, self.streams.get(key)) else {
+                continue;
+            };
+            let first = from_highest.map_or(0, |x| x.saturating_add(1));
+            lost += stream.missing(first, *highest);
+        }
+        (lost, duplicates)
+    }
+
+    /// Messages received from each friend, `lost` is left to `reconcile`.
+    pub fn summary(&self) -> Vec<DeliverySummary> {
+        let mut summary: Vec<DeliverySummary> = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| !stream.seen.is_empty())
+            .map(|((friend, msg_type), stream)| DeliverySummary {
+                friend: friend.clone(),
+                r#type: msg_type.clone(),
+                received: stream.received,
+                duplicates: stream.duplicates,
+                reordered: stream.reordered,
+                gaps: stream.gaps(),
+                lost: None,
+            })
+            .collect();
+        summary.sort_by(|a, b| (&a.friend, &a.r#type).cmp(&(&b.friend, &b.r#type)));
+        summary
+    }
+
+    pub fn sent_summary(&self) -> Vec<SentSummary> {
+        let mut summary: Vec<SentSummary> = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.sent > 0)
+            .map(|((friend, msg_type), stream)| SentSummary {
+                friend: friend.clone(),
+                r#type: msg_type.clone(),
+                sent: stream.sent,
+            })
+            .collect();
+        summary.sort_by(|a, b| (&a.friend, &a.r#type).cmp(&(&b.friend, &b.r#type)));
+        summary
+    }
+
+    fn stream(&mut self, friend: &str, msg_type: &MessageType) -> &mut Stream {
+        self.streams
+            .entry((friend.to_string(), msg_type.clone()))
+            .or_default()
+    }
+}
+
+/// Fills in the messages lost on every channel by comparing what the receiver got with what
+/// the sender reports as sent, which also covers losses after the highest sequence received.
+/// `reports` are keyed by username, channels whose sender did not report stay unreconciled.
+pub fn reconcile(reports: &mut HashMap<String, ClientReport>) {
+    let sent: HashMap<(String, String, MessageType), u64> = reports
+        .iter()
+        .flat_map(|(sender, report)| {
+            report.sent.iter().map(move |sent| {
+                let key = (sender.clone(), sent.friend.clone(), sent.r#type.clone());
+                (key, sent.sent)
+            })
+        })
+        .collect();
+    for (receiver, report) in reports.iter_mut() {
+        for summary in &mut report.delivery {
+            let key = (
+                summary.friend.clone(),
+                receiver.clone(),
+                summary.r#type.clone(),
+            );
+            summary.lost = sent
+                .get(&key)
+                .map(|sent| sent.saturating_sub(summary.received));
+        }
+        // channels on which not a single message arrived
+        for ((sender, to, msg_type), sent) in &sent {
+            let received = report
+                .delivery
+                .iter()
+                .any(|summary| &summary.friend == sender && &summary.r#type == msg_type);
+            if to == receiver && !received {
+                report.delivery.push(DeliverySummary {
+                    friend: sender.clone(),
+                    r#type: msg_type.clone(),
+                    received: 0,
+                    duplicates: 0,
+                    reordered: 0,
+                    gaps: Vec::new(),
+                    lost: Some(*sent),
+                });
+            }
+        }
+        report
+            .delivery
+            .sort_by(|a, b| (&a.friend, &a.r#type).cmp(&(&b.friend, &b.r#type)));
+    }
+}
+
+/// Fills in the messages lost around each restart: those the other clients sent since the
+/// previous restart, or the start of the run, that the restarted client never received.
+/// This includes the messages in flight when the client was dropped, and the last restart also
+/// counts everything sent after it. Unlike `DeliveryTracker::between` this covers losses after
+/// the last message received, but only messages found in the senders' logs are counted.
+pub fn reconcile_restarts(reports: &mut HashMap<String, ClientReport>) {
+    // messages sent to each receiver, as (sender, type, sequence, send time)
+    let mut sent: HashMap<String, Vec<(String, MessageType, u64, u128)>> = HashMap::new();
+    for (sender, report) in reports.iter() {
+        for message in &report.messages {
+            if message.receive_time.is_some() || &message.from != sender {
+                continue;
+            }
+            if let (Some(sequence), Some(send_time)) = (message.sequence, message.send_time) {
+                sent.entry(message.to.clone()).or_default().push((
+                    sender.clone(),
+                    message.r#type.clone(),
+                    sequence,
+                    send_time,
+                ));
+            }
+        }
+    }
+    for (receiver, report) in reports.iter_mut() {
+        if report.restarts.is_empty() {
+            continue;
+        }
+        let received: HashSet<(&str, &MessageType, u64)> = report
+            .messages
+            .iter()
+            .filter(|message| message.receive_time.is_some())
+            .filter_map(|message| {
+                let sequence = message.sequence?;
+                Some((message.from.as_str(), &message.r#type, sequence))
+            })
+            .collect();
+        let sent = sent.get(receiver).map(Vec::as_slice).unwrap_or_default();
+        let times: Vec<u128> = report.restarts.iter().map(|restart| restart.time).collect();
+        let mut lost = vec![0; times.len()];
+        let missing = sent.iter().filter(|(sender, msg_type, sequence, _)| {
+            !received.contains(&(sender.as_str(), msg_type, *sequence))
+        });
+        for (_, _, _, send_time) in missing {
+            // the first restart the message had not arrived by
+            let restart = times.partition_point(|time| time < send_time);
+            lost[restart.min(times.len() - 1)] += 1;
+        }
+        for (restart, lost) in report.restarts.iter_mut().zip(lost) {
+            restart.lost = Some(lost);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{MessageLog, RestartLog};
+
+    fn receive(tracker: &mut DeliveryTracker, sequences: &[u64]) -> Vec<Delivery> {
+        sequences
+            .iter()
+            .map(|&sequence| tracker.received("bob", &MessageType::Regular, sequence))
+            .collect()
+    }
+
+    fn report(delivery: Vec<DeliverySummary>, sent: Vec<SentSummary>) -> ClientReport {
+        ClientReport::builder()
+            .start_time(0)
+            .seed(0)
+            .phase_offset_millis(0)
+            .partial(false)
+            .tick_lateness(Vec::new())
+            .messages(Vec::new())
+            .delivery(delivery)
+            .sent(sent)
+            .integrity(Vec::new())
+            .conversations(Vec::new())
+            .group_messages(Vec::new())
+            .offline_periods(Vec::new())
+            .reconnects(Vec::new())
+            .faults(Vec::new())
+            .restarts(Vec::new())
+            .build()
+    }
+
+    #[test]
+    fn in_order() {
+        let mut tracker = DeliveryTracker::default();
+        let deliveries = receive(&mut tracker, &[0, 1, 2]);
+        assert!(deliveries.iter().all(|d| *d == Delivery::InOrder));
+
+        let summary = tracker.summary();
+        assert_eq!(summary.len(), 1);
+        assert_eq!(summary[0].received, 3);
+        assert_eq!(summary[0].duplicates, 0);
+        assert_eq!(summary[0].reordered, 0);
+        assert!(summary[0].gaps.is_empty());
+        assert_eq!(summary[0].lost, None);
+    }
+
+    #[test]
+    fn gaps() {
+        let mut tracker = DeliveryTracker::default();
+        receive(&mut tracker, &[1, 2, 5, 9]);
+
+        let summary = tracker.summary();
+        assert_eq!(
+            summary[0].gaps,
+            vec![
+                SequenceGap { start: 0, end: 1 },
+                SequenceGap { start: 3, end: 5 },
+                SequenceGap { start: 6, end: 9 },
+            ]
+        );
+        assert_eq!(summary[0].received, 4);
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut tracker = DeliveryTracker::default();
+        let deliveries = receive(&mut tracker, &[0, 1, 1, 0]);
+        assert_eq!(
+            deliveries,
+            vec![
+                Delivery::InOrder,
+                Delivery::InOrder,
+                Delivery::Duplicate,
+                Delivery::Duplicate,
+            ]
+        );
+
+        let summary = tracker.summary();
+        assert_eq!(summary[0].received, 2);
+        assert_eq!(summary[0].duplicates, 2);
+        assert_eq!(summary[0].reordered, 0);
+    }
+
+    #[test]
+    fn reordered() {
+        let mut tracker = DeliveryTracker::default();
+        let deliveries = receive(&mut tracker, &[0, 2, 1, 3]);
+        assert_eq!(
+            deliveries,
+            vec![
+                Delivery::InOrder,
+                Delivery::InOrder,
+                Delivery::Reordered,
+                Delivery::InOrder,
+            ]
+        );
+
+        let summary = tracker.summary();
+        assert_eq!(summary[0].reordered, 1);
+        // the late message fills the gap it left
+        assert!(summary[0].gaps.is_empty());
+    }
+
+    #[test]
+    fn streams_are_separate() {
+        let mut tracker = DeliveryTracker::default();
+        tracker.received("bob", &MessageType::Regular, 0);
+        tracker.received("bob", &MessageType::Denim, 0);
+        tracker.received("carol", &MessageType::Regular, 0);
+
+        let summary = tracker.summary();
+        assert_eq!(summary.len(), 3);
+        assert!(summary.iter().all(|s| s.received == 1 && s.duplicates == 0));
+    }
+
+    #[test]
+    fn sequences() {
+        let mut tracker = DeliveryTracker::default();
+        assert_eq!(tracker.next_sequence("bob", &MessageType::Regular), 0);
+        tracker.sent("bob", &MessageType::Regular);
+        tracker.sent("bob", &MessageType::Regular);
+        assert_eq!(tracker.next_sequence("bob", &MessageType::Regular), 2);
+        assert_eq!(tracker.next_sequence("bob", &MessageType::Denim), 0);
+
+        let sent = tracker.sent_summary();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].sent, 2);
+        // nothing was received, so there is nothing to summarize
+        assert!(tracker.summary().is_empty());
+    }
+
+    #[test]
+    fn between_checkpoints() {
+        let mut tracker = DeliveryTracker::default();
+        receive(&mut tracker, &[0, 2]);
+        let first = tracker.checkpoint();
+        receive(&mut tracker, &[2, 5, 6]);
+        let second = tracker.checkpoint();
+        receive(&mut tracker, &[6, 6, 8]);
+
+        // 3 and 4 are missing, 2 arrived again
+        assert_eq!(tracker.between(&first, Some(&second)), (2, 1));
+        // 7 is missing, 6 arrived twice more
+        assert_eq!(tracker.between(&second, None), (1, 2));
+    }
+
+    #[test]
+    fn reconcile_lost() {
+        let mut bob = DeliveryTracker::default();
+        // 1 is missing and nothing arrived after 2 of the 5 messages alice sent
+        bob.received("alice", &MessageType::Regular, 0);
+        bob.received("alice", &MessageType::Regular, 2);
+        let mut alice = DeliveryTracker::default();
+        for _ in 0..5 {
+            alice.sent("bob", &MessageType::Regular);
+        }
+        alice.sent("bob", &MessageType::Denim);
+
+        let mut reports = HashMap::from([
+            (
+                "alice".to_string(),
+                report(Vec::new(), alice.sent_summary()),
+            ),
+            ("bob".to_string(), report(bob.summary(), Vec::new())),
+        ]);
+        reconcile(&mut reports);
+
+        let delivery = &reports["bob"].delivery;
+        assert_eq!(delivery.len(), 2);
+        assert_eq!(delivery[0].r#type, MessageType::Denim);
+        assert_eq!(delivery[0].received, 0);
+        assert_eq!(delivery[0].lost, Some(1));
+        assert_eq!(delivery[1].r#type, MessageType::Regular);
+        assert_eq!(delivery[1].lost, Some(3));
+        assert!(reports["alice"].delivery.is_empty());
+    }
+
+    fn message(from: &str, to: &str, sequence: u64, send_time: u128, received: bool) -> MessageLog {
+        MessageLog::builder()
+            .sequence(sequence)
+            .r#type(MessageType::Regular)
+            .from(from.to_string())
+            .to(to.to_string())
+            .size(0)
+            .tick(0)
+            .send_time(send_time)
+            .maybe_receive_time(received.then_some(send_time + 10))
+            .build()
+    }
+
+    fn restart(time: u128) -> RestartLog {
+        RestartLog {
+            tick: 0,
+            time,
+            recovery_millis: 0,
+            recovered: true,
+            gaps: 0,
+            lost: None,
+            duplicates: 0,
+        }
+    }
+
+    #[test]
+    fn reconcile_restart_losses() {
+        let mut alice = report(Vec::new(), Vec::new());
+        alice.messages = (0..6)
+            .map(|sequence| message("alice", "bob", sequence, 100 * sequence as u128, false))
+            .collect();
+        // a message to someone else is not lost by bob
+        alice
+            .messages
+            .push(message("alice", "carol", 0, 350, false));
+        let mut bob = report(Vec::new(), Vec::new());
+        // 1 is in flight when bob is dropped at the first restart, 4 and 5 are sent after the
+        // last message that arrived, which only the senders' logs reveal
+        bob.messages = [0, 2, 3]
+            .into_iter()
+            .map(|sequence| message("alice", "bob", sequence, 100 * sequence as u128, true))
+            .collect();
+        bob.restarts = vec![restart(150), restart(350)];
+
+        let mut reports = HashMap::from([("alice".to_string(), alice), ("bob".to_string(), bob)]);
+        reconcile_restarts(&mut reports);
+
+        let lost: Vec<Option<u64>> = reports["bob"].restarts.iter().map(|r| r.lost).collect();
+        assert_eq!(lost, vec![Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn reconcile_without_sender() {
+        let mut bob = DeliveryTracker::default();
+        bob.received("alice", &MessageType::Regular, 0);
+
+        let mut reports = HashMap::from([("bob".to_string(), report(bob.summary(), Vec::new()))]);
+        reconcile(&mut reports);
+
+        assert_eq!(reports["bob"].delivery[0].lost, None);
+    }
+}
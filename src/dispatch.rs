@@ -1,26 +1,59 @@
-use crate::data::{AccountInfo, ClientInfo, ClientReport, StartInfo};
+use std::sync::Arc;
+
+use crate::data::{AccountInfo, ClientInfo, ClientReport, LogBatch, StartInfo};
 use derive_more::{Display, Error, From};
+use reqwest::{
+    Url,
+    cookie::{CookieStore, Jar},
+};
+
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
 
+#[derive(Clone)]
 pub struct SamDispatchClient {
     url: String,
     client: reqwest::Client,
+    cookies: Arc<Jar>,
+    cookie_url: Url,
 }
 
 #[derive(Debug, Display, Error, From)]
 pub enum SamDispatchError {
     Json(serde_json::Error),
     Reqwest(reqwest::Error),
+    Url(url::ParseError),
     Unauthorized,
+    Status(#[error(not(source))] reqwest::StatusCode),
 }
 
 impl SamDispatchClient {
     pub fn new(url: String) -> Result<Self, SamDispatchError> {
+        let url = format!("http://{}", url);
+        let cookies = Arc::new(Jar::default());
         Ok(Self {
-            url: format!("http://{}", url),
-            client: reqwest::Client::builder().cookie_store(true).build()?,
+            cookie_url: Url::parse(&url)?,
+            url,
+            client: reqwest::Client::builder()
+                .cookie_provider(cookies.clone())
+                .build()?,
+            cookies,
         })
     }
 
+    /// Cookies identifying this client to the dispatcher, if it has been assigned a session.
+    pub fn session(&self) -> Option<String> {
+        self.cookies
+            .cookies(&self.cookie_url)
+            .and_then(|cookies| cookies.to_str().ok().map(str::to_string))
+    }
+
+    /// Continues a session previously obtained with [`SamDispatchClient::session`].
+    pub fn restore_session(&self, session: &str) {
+        for cookie in session.split(';') {
+            self.cookies.add_cookie_str(cookie.trim(), &self.cookie_url);
+        }
+    }
+
     pub async fn health(&self) -> bool {
         let res = self.client.get(format!("{}/health", self.url)).send().await;
         match res {
@@ -47,18 +80,32 @@ impl SamDispatchClient {
         Ok(res.json().await?)
     }
 
-    pub async fn upload_results(&self, report: ClientReport) -> Result<(), SamDispatchError> {
-        let json_val = serde_json::to_string(&report)?;
+    /// Uploads the report, the dispatcher ignores repeated uploads with the same `idempotency_key`.
+    pub async fn upload_results(
+        &self,
+        report: &ClientReport,
+        idempotency_key: &str,
+    ) -> Result<(), SamDispatchError> {
+        let json_val = serde_json::to_string(report)?;
         let res = self
             .client
             .post(format!("{}/upload", self.url))
+            .header(IDEMPOTENCY_KEY, idempotency_key)
             .body(json_val)
             .send()
             .await?;
-        if res.status() == reqwest::StatusCode::UNAUTHORIZED {
-            return Err(SamDispatchError::Unauthorized);
-        }
-        Ok(())
+        check_status(res.status())
+    }
+
+    pub async fn upload_batch(&self, batch: &LogBatch) -> Result<(), SamDispatchError> {
+        let json_val = serde_json::to_string(batch)?;
+        let res = self
+            .client
+            .post(format!("{}/upload/batch", self.url))
+            .body(json_val)
+            .send()
+            .await?;
+        check_status(res.status())
     }
 
     pub async fn upload_account_id(&self, account_id: AccountInfo) -> Result<(), SamDispatchError> {
@@ -69,9 +116,16 @@ impl SamDispatchClient {
             .body(json_val)
             .send()
             .await?;
-        if res.status() == reqwest::StatusCode::UNAUTHORIZED {
-            return Err(SamDispatchError::Unauthorized);
-        }
+        check_status(res.status())
+    }
+}
+
+fn check_status(status: reqwest::StatusCode) -> Result<(), SamDispatchError> {
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        Err(SamDispatchError::Unauthorized)
+    } else if !status.is_success() {
+        Err(SamDispatchError::Status(status))
+    } else {
         Ok(())
     }
 }
@@ -0,0 +1,192 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use log::{error, info, warn};
+use rand::{SeedableRng, rngs::StdRng};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        TcpListener, TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::{Mutex, mpsc, watch},
+    time::Instant,
+};
+
+use crate::{
+    config::FaultProfile,
+    data::{Direction, Fault},
+    utils::{now_millis, random_delay, sample_prob},
+};
+
+const CHUNK_SIZE: usize = 16 * 1024;
+/// Chunks waiting for their release time, a full queue stops reading from the sender.
+const QUEUED_CHUNKS: usize = 64;
+
+pub type ArcFaults = Arc<Mutex<Vec<InjectedFault>>>;
+
+#[derive(Clone, Debug)]
+pub struct InjectedFault {
+    pub time: u128,
+    pub direction: Direction,
+    pub fault: Fault,
+}
+
+/// Local TCP proxy in front of the server that degrades traffic according to a `FaultProfile`.
+pub struct FaultProxy {
+    address: SocketAddr,
+    faults: ArcFaults,
+}
+
+impl FaultProxy {
+    pub async fn start(upstream: String, profile: FaultProfile) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let faults = ArcFaults::default();
+        info!("Injecting faults into traffic to '{upstream}' through '{address}'");
+        tokio::spawn(accept(listener, upstream, profile, faults.clone()));
+        Ok(Self { address, faults })
+    }
+
+    /// Address clients connect to instead of the server.
+    pub fn address(&self) -> String {
+        self.address.to_string()
+    }
+
+    pub fn faults(&self) -> ArcFaults {
+        self.faults.clone()
+    }
+}
+
+async fn accept(listener: TcpListener, upstream: String, profile: FaultProfile, faults: ArcFaults) {
+    loop {
+        let client = match listener.accept().await {
+            Ok((client, _)) => client,
+            Err(e) => {
+                error!("Fault proxy failed to accept a connection: {e}");
+                continue;
+            }
+        };
+        let upstream = upstream.clone();
+        let faults = faults.clone();
+        tokio::spawn(async move {
+            let server = match TcpStream::connect(&upstream).await {
+                Ok(server) => server,
+                Err(e) => {
+                    error!("Fault proxy failed to connect to '{upstream}': {e}");
+                    return;
+                }
+            };
+            let _ = client.set_nodelay(true);
+            let _ = server.set_nodelay(true);
+            let (client_read, client_write) = client.into_split();
+            let (server_read, server_write) = server.into_split();
+            // a reset in one direction resets the other as well, so both ends see it
+            let reset = Arc::new(watch::channel(false).0);
+            tokio::spawn(pump(
+                client_read,
+                server_write,
+                Direction::Upstream,
+                profile,
+                faults.clone(),
+                reset.clone(),
+            ));
+            tokio::spawn(pump(
+                server_read,
+                client_write,
+                Direction::Downstream,
+                profile,
+                faults,
+                reset,
+            ));
+        });
+    }
+}
+
+/// Reads chunks from `from` and schedules each one for release at its arrival plus its delay,
+/// so the delays of consecutive chunks do not add up.
+async fn pump(
+    mut from: OwnedReadHalf,
+    to: OwnedWriteHalf,
+    direction: Direction,
+    profile: FaultProfile,
+    faults: ArcFaults,
+    reset: Arc<watch::Sender<bool>>,
+) {
+    let mut rng = StdRng::from_entropy();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut reset_requested = reset.subscribe();
+    let (queue, queued) = mpsc::channel(QUEUED_CHUNKS);
+    let writer = tokio::spawn(release(queued, to));
+    // time the simulated link is busy sending earlier chunks until
+    let mut link_free = Instant::now();
+    let mut last_release = Instant::now();
+    loop {
+        let read = tokio::select! {
+            read = from.read(&mut buf) => read,
+            _ = reset_requested.wait_for(|reset| *reset) => break,
+        };
+        let read = match read {
+            Ok(0) | Err(_) => return,
+            Ok(read) => read,
+        };
+        let arrival = Instant::now();
+
+        if sample_prob(profile.reset_probability.unwrap_or(0.0), &mut rng) {
+            warn!("Fault proxy resets a connection");
+            record(&faults, direction, Fault::Reset).await;
+            reset.send_replace(true);
+            break;
+        }
+
+        let mut delay = Duration::from_millis(profile.latency_millis.unwrap_or(0).into())
+            + random_delay(profile.jitter_millis, &mut rng);
+        if sample_prob(profile.spike_probability.unwrap_or(0.0), &mut rng) {
+            let delay_millis = profile.spike_millis.unwrap_or(0);
+            delay += Duration::from_millis(delay_millis.into());
+            record(&faults, direction, Fault::DelaySpike { delay_millis }).await;
+        }
+        let sent = match profile.bandwidth_bytes_per_second {
+            Some(bytes_per_second) => {
+                let transmission =
+                    Duration::from_secs_f64(read as f64 / bytes_per_second.max(1) as f64);
+                link_free = link_free.max(arrival) + transmission;
+                link_free
+            }
+            None => arrival,
+        };
+        // TCP keeps the order of the bytes, so jitter cannot let a chunk overtake an earlier one
+        last_release = (sent + delay).max(last_release);
+
+        if queue
+            .send((last_release, buf[..read].to_vec()))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+    // with a zero linger, closing the socket sends a RST instead of a FIN
+    if let Err(e) = from.as_ref().set_zero_linger() {
+        error!("Fault proxy failed to reset a connection: {e}");
+    }
+    writer.abort();
+}
+
+/// Writes every chunk to `to` once its release time has come, then closes the connection.
+async fn release(mut queued: mpsc::Receiver<(Instant, Vec<u8>)>, mut to: OwnedWriteHalf) {
+    while let Some((release, chunk)) = queued.recv().await {
+        tokio::time::sleep_until(release).await;
+        if to.write_all(&chunk).await.is_err() {
+            return;
+        }
+    }
+    let _ = to.shutdown().await;
+}
+
+async fn record(faults: &ArcFaults, direction: Direction, fault: Fault) {
+    faults.lock().await.push(InjectedFault {
+        time: now_millis(),
+        direction,
+        fault,
+    });
+}
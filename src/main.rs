@@ -1,23 +1,60 @@
-use std::{io::BufReader, time::Duration};
+use std::{
+    collections::HashMap,
+    io::{BufReader, BufWriter},
+    num::NonZeroUsize,
+    path::PathBuf,
+    time::Duration,
+};
 
 use clap::{Arg, Command};
 use config::DenimClientConfig;
-use data::{AccountInfo, DispatchData};
+use data::{AccountInfo, ClientInfo, DispatchData, StartInfo};
 use derive_more::{Display, Error, From};
 use dispatch::{SamDispatchClient, SamDispatchError};
+use env_logger::Env;
+use fault::FaultProxy;
+use futures::future::{join_all, try_join_all};
 use health::HealthClient;
-use log::{error, info};
+use log::{error, info, warn};
+use mock_dispatch::{MockDispatchError, Population};
+use rustls::ClientConfig;
+use sam_common::AccountId;
 use sam_net::{error::ClientTlsError, tls::create_tls_client_config};
-use scenario::ScenarioRunner;
-use test_client::{TestClient, TestClientCreationError};
+use scenario::{ScenarioError, ScenarioRunner};
+use serde::de::DeserializeOwned;
+use spool::{Spool, SpoolError, SpooledReport, upload_spooled};
+use storage::{StoreError, Stores};
+use stream::upload_batches;
+use test_client::{ClientSettings, TestClientCreationError};
+use tokio::{
+    signal::{
+        self,
+        unix::{Signal, SignalKind},
+    },
+    sync::{mpsc, watch},
+    task::JoinError,
+};
+use tokio_util::task::LocalPoolHandle;
 
+mod churn;
 mod config;
+mod content;
+mod conversation;
 mod data;
+mod delivery;
 mod dispatch;
+mod fault;
 mod health;
+mod mock_dispatch;
+mod payload;
 mod scenario;
+mod size;
+mod spool;
+mod storage;
+mod stream;
 mod test_client;
 mod timer;
+mod traffic;
 mod utils;
 
 #[derive(Debug, Display, Error, From)]
@@ -26,51 +63,415 @@ pub enum CliError {
     Serde(serde_json::Error),
     Io(std::io::Error),
     Dispatch(SamDispatchError),
+    MockDispatch(MockDispatchError),
     ArgumentError(#[error(not(source))] String),
     Tls(ClientTlsError),
     Creation(TestClientCreationError),
+    Spool(SpoolError),
+    Store(StoreError),
+    Scenario(ScenarioError),
     Reqwest(reqwest::Error),
+    Join(JoinError),
     UnknownClientType,
 }
 
 const DEFAULT_CHANNEL_BUFFER_SIZE: usize = 10;
+const DEFAULT_SPOOL_DIRECTORY: &str = "spool";
+/// Time left for uploads once a shutdown is requested, well within Docker's 10s stop grace period.
+const SHUTDOWN_UPLOAD_DEADLINE: Duration = Duration::from_secs(5);
 
 async fn cli() -> Result<(), CliError> {
     let matches = Command::new("denim_client")
-        .arg(Arg::new("config").required(true).help("Client config"))
+        .arg(Arg::new("config").help("Client config"))
+        .subcommand(
+            Command::new("standalone")
+                .about("Run a scenario from local files without a dispatcher")
+                .arg(Arg::new("config").required(true).help("Client config"))
+                .arg(
+                    Arg::new("client")
+                        .long("client")
+                        .required(true)
+                        .help("ClientInfo JSON file"),
+                )
+                .arg(
+                    Arg::new("friends")
+                        .long("friends")
+                        .required(true)
+                        .help("JSON file mapping friend usernames to account ids"),
+                )
+                .arg(
+                    Arg::new("report")
+                        .long("report")
+                        .default_value("report.json")
+                        .help("File the client report is written to"),
+                ),
+        )
+        .subcommand(
+            Command::new("dispatcher")
+                .about("Serve a mock dispatcher for a population of clients")
+                .arg(
+                    Arg::new("population")
+                        .required(true)
+                        .help("Population definition file"),
+                )
+                .arg(
+                    Arg::new("address")
+                        .long("address")
+                        .default_value("0.0.0.0:8080")
+                        .help("Address the dispatcher listens on"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .default_value("reports")
+                        .help("Directory client reports are written to"),
+                ),
+        )
+        .subcommand(
+            Command::new("reupload")
+                .about("Upload reports left in the spool directory by failed runs")
+                .arg(Arg::new("config").required(true).help("Client config")),
+        )
+        .args_conflicts_with_subcommands(true)
         .get_matches();
 
+    if let Some(standalone) = matches.subcommand_matches("standalone") {
+        let config_path = standalone
+            .get_one::<String>("config")
+            .ok_or(CliError::NoConfig)?;
+        let client_path = standalone
+            .get_one::<String>("client")
+            .ok_or(CliError::ArgumentError("client".to_string()))?;
+        let friends_path = standalone
+            .get_one::<String>("friends")
+            .ok_or(CliError::ArgumentError("friends".to_string()))?;
+        let report_path = standalone
+            .get_one::<String>("report")
+            .ok_or(CliError::ArgumentError("report".to_string()))?;
+
+        let config = read_config(config_path)?;
+        init_logging(&config);
+        let client_info: ClientInfo = read_json(client_path)?;
+        let friends: HashMap<String, AccountId> = read_json(friends_path)?;
+        return run_standalone(config, client_info, friends, report_path).await;
+    }
+
+    if let Some(reupload_matches) = matches.subcommand_matches("reupload") {
+        let config_path = reupload_matches
+            .get_one::<String>("config")
+            .ok_or(CliError::NoConfig)?;
+        let config = read_config(config_path)?;
+        init_logging(&config);
+        return reupload(config).await;
+    }
+
+    if let Some(dispatcher) = matches.subcommand_matches("dispatcher") {
+        let population_path = dispatcher
+            .get_one::<String>("population")
+            .ok_or(CliError::ArgumentError("population".to_string()))?;
+        let address = dispatcher
+            .get_one::<String>("address")
+            .ok_or(CliError::ArgumentError("address".to_string()))?;
+        let output = dispatcher
+            .get_one::<String>("output")
+            .ok_or(CliError::ArgumentError("output".to_string()))?;
+
+        env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+        let population: Population = read_json(population_path)?;
+        return Ok(mock_dispatch::serve(population, address, PathBuf::from(output)).await?);
+    }
+
     let config_path = matches
         .get_one::<String>("config")
         .ok_or(CliError::NoConfig)?;
+    let config = read_config(config_path)?;
+    init_logging(&config);
+    run_dispatched(config).await
+}
+
+async fn run_dispatched(config: DenimClientConfig) -> Result<(), CliError> {
+    let tls = tls_config(&config)?;
+    let client_count = config.client_count.unwrap_or(1).max(1);
+    // every simulated client needs its own cookie jar to hold its dispatcher session
+    let dispatchers = (0..client_count)
+        .map(|_| SamDispatchClient::new(config.dispatch_address.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    while !dispatchers[0].health().await {
+        info!("Dispatcher unavailable, trying again in 200ms...");
+        tokio::time::sleep(Duration::from_millis(200)).await
+    }
+    info!("Dispatcher ready!");
+    let client_infos =
+        try_join_all(dispatchers.iter().map(|dispatch| dispatch.get_client())).await?;
+
+    wait_for_server(&config, tls.clone()).await?;
+    let proxies = try_join_all(client_infos.iter().map(|_| fault_proxy(&config))).await?;
+    let settings = client_infos
+        .iter()
+        .zip(&proxies)
+        .map(|(client_info, proxy)| client_settings(&config, client_info, tls.clone(), proxy))
+        .collect::<Result<Vec<_>, _>>()?;
+    let clients = try_join_all(settings.iter().map(ClientSettings::create)).await?;
+    info!("Registered {} client(s)", clients.len());
+    let stores: Vec<Stores> = settings
+        .iter()
+        .map(|settings| settings.stores.clone())
+        .collect();
+
+    try_join_all(dispatchers.iter().zip(&clients).map(|(dispatch, client)| {
+        dispatch.upload_account_id(
+            AccountInfo::builder()
+                .account_id(client.account_id())
+                .build(),
+        )
+    }))
+    .await?;
+
+    let start_infos = try_join_all(dispatchers.iter().map(|dispatch| dispatch.sync())).await?;
+    let usernames: Vec<String> = client_infos
+        .iter()
+        .map(|client_info| client_info.username.clone())
+        .collect();
+    let shutdown = shutdown_on_signal();
+    let mut uploaders = Vec::new();
+    let runners: Vec<ScenarioRunner> = client_infos
+        .into_iter()
+        .zip(start_infos)
+        .zip(clients)
+        .zip(&dispatchers)
+        .zip(&proxies)
+        .zip(settings)
+        .map(
+            |(((((client_info, start_info), client), dispatch), proxy), settings)| {
+                let mut runner =
+                    ScenarioRunner::new(DispatchData::new(client_info, start_info), client)?
+                        .shutdown_on(shutdown.clone())
+                        .restart_from(settings);
+                if let Some(proxy) = proxy {
+                    runner = runner.inject_faults(proxy.faults());
+                }
+                Ok(match config.log_flush_ticks {
+                    Some(flush_ticks) => {
+                        let (batches, recv) = mpsc::unbounded_channel();
+                        uploaders.push(Some(tokio::spawn(upload_batches(
+                            dispatch.clone(),
+                            recv,
+                            shutdown_deadline(shutdown.clone()),
+                        ))));
+                        runner.stream_logs(batches, flush_ticks)
+                    }
+                    None => {
+                        uploaders.push(None);
+                        runner
+                    }
+                })
+            },
+        )
+        .collect::<Result<_, CliError>>()?;
+
+    // runners are not `Send` once started, so each worker thread drives its share of them
+    let workers = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let pool = LocalPoolHandle::new(workers.min(runners.len()).max(1));
+    info!("Starting Scenario...");
+    let mut reports = join_all(
+        runners
+            .into_iter()
+            .map(|runner| pool.spawn_pinned(move || runner.start())),
+    )
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+    // batches have to reach the dispatcher before the report marks the run as complete,
+    // those that did not are uploaded with the report
+    for (report, uploader) in reports.iter_mut().zip(uploaders) {
+        if let Some(uploader) = uploader {
+            report.unsent_batches = uploader.await?;
+        }
+    }
+    // once a shutdown is requested whatever is not uploaded by the deadline is left behind
+    let deadline = shutdown_deadline(shutdown);
+    tokio::pin!(deadline);
+    // reports are spooled first, so a failed upload can be retried with `reupload`
+    let spool = Spool::new(spool_directory(&config))?;
+    let spooled = dispatchers
+        .iter()
+        .zip(usernames)
+        .zip(reports)
+        .map(|((dispatch, username), report)| {
+            let report = SpooledReport::new(username, dispatch.session(), report);
+            let path = spool.store(&report)?;
+            Ok((dispatch, report, path))
+        })
+        .collect::<Result<Vec<_>, CliError>>()?;
+    for (stores, (_, report, _)) in stores.iter().zip(&spooled) {
+        finish_stores(stores, report.report.start_time);
+    }
+
+    let uploads = join_all(
+        spooled
+            .iter()
+            .map(|(dispatch, report, path)| upload_spooled(dispatch, report, path)),
+    );
+    tokio::select! {
+        results = uploads => {
+            results.into_iter().collect::<Result<Vec<_>, _>>()?;
+        }
+        _ = &mut deadline => warn!(
+            "Shutting down, reports left in '{}' can be uploaded with `reupload`",
+            spool_directory(&config)
+        ),
+    }
+
+    Ok(())
+}
 
-    let file = std::fs::File::open(config_path)?;
+async fn reupload(config: DenimClientConfig) -> Result<(), CliError> {
+    let spool = Spool::new(spool_directory(&config))?;
+    let pending = spool.pending()?;
+    info!("Found {} spooled report(s)", pending.len());
+
+    let mut result = Ok(());
+    for path in pending {
+        let uploaded = async {
+            let report = Spool::load(&path)?;
+            let dispatch = SamDispatchClient::new(config.dispatch_address.clone())?;
+            if let Some(session) = &report.session {
+                dispatch.restore_session(session);
+            }
+            upload_spooled(&dispatch, &report, &path).await
+        };
+        if let Err(e) = uploaded.await {
+            error!("Failed to re-upload '{}': {e}", path.display());
+            result = Err(e.into());
+        }
+    }
+    result
+}
+
+fn spool_directory(config: &DenimClientConfig) -> &str {
+    config
+        .spool_directory
+        .as_deref()
+        .unwrap_or(DEFAULT_SPOOL_DIRECTORY)
+}
+
+async fn run_standalone(
+    config: DenimClientConfig,
+    client_info: ClientInfo,
+    friends: HashMap<String, AccountId>,
+    report_path: &str,
+) -> Result<(), CliError> {
+    let tls = tls_config(&config)?;
+
+    wait_for_server(&config, tls.clone()).await?;
+    let proxy = fault_proxy(&config).await?;
+    let settings = client_settings(&config, &client_info, tls, &proxy)?;
+    let stores = settings.stores.clone();
+    let client = settings.create().await?;
+    info!(
+        "Registered '{}' with account id '{}'",
+        client_info.username,
+        client.account_id()
+    );
+
+    let dispatch_data = DispatchData::new(client_info, StartInfo { friends });
+
+    let mut runner = ScenarioRunner::new(dispatch_data, client)?
+        .shutdown_on(shutdown_on_signal())
+        .restart_from(settings);
+    if let Some(proxy) = &proxy {
+        runner = runner.inject_faults(proxy.faults());
+    }
+    info!("Starting Scenario...");
+    let report = runner.start().await;
+
+    let file = std::fs::File::create(report_path)?;
+    serde_json::to_writer_pretty(BufWriter::new(file), &report)?;
+    info!("Wrote report to '{report_path}'");
+    finish_stores(&stores, report.start_time);
+
+    Ok(())
+}
+
+/// Listens for SIGINT and SIGTERM, the returned receiver becomes true once either arrives.
+/// A second signal exits right away.
+fn shutdown_on_signal() -> watch::Receiver<bool> {
+    let (shutdown, recv) = watch::channel(false);
+    tokio::spawn(async move {
+        let mut terminate = signal::unix::signal(SignalKind::terminate())
+            .inspect_err(|e| error!("Failed to listen for SIGTERM: {e}"))
+            .ok();
+        let signal = next_signal(&mut terminate).await;
+        info!("Received {signal}, shutting down...");
+        shutdown.send_replace(true);
+        let signal = next_signal(&mut terminate).await;
+        warn!("Received {signal} again, exiting without a report");
+        std::process::exit(1);
+    });
+    recv
+}
+
+async fn next_signal(terminate: &mut Option<Signal>) -> &'static str {
+    let terminate = async {
+        match terminate {
+            Some(terminate) => {
+                terminate.recv().await;
+            }
+            None => std::future::pending::<()>().await,
+        }
+    };
+    tokio::select! {
+        _ = signal::ctrl_c() => "SIGINT",
+        _ = terminate => "SIGTERM",
+    }
+}
+
+/// Completes `SHUTDOWN_UPLOAD_DEADLINE` after a shutdown was requested, never without one.
+async fn shutdown_deadline(mut shutdown: watch::Receiver<bool>) {
+    if shutdown.wait_for(|shutdown| *shutdown).await.is_err() {
+        std::future::pending::<()>().await
+    }
+    tokio::time::sleep(SHUTDOWN_UPLOAD_DEADLINE).await
+}
+
+fn read_json<T: DeserializeOwned>(path: &str) -> Result<T, CliError> {
+    let file = std::fs::File::open(path)?;
     let reader = BufReader::new(file);
-    let config: DenimClientConfig = serde_json::from_reader(reader)?;
+    Ok(serde_json::from_reader(reader)?)
+}
 
+/// Reads the client config and rejects settings that could only fail later in the run.
+fn read_config(path: &str) -> Result<DenimClientConfig, CliError> {
+    let config: DenimClientConfig = read_json(path)?;
+    if let Some(storage) = &config.storage {
+        storage::check_config(storage)?;
+    }
+    Ok(config)
+}
+
+fn init_logging(config: &DenimClientConfig) {
     if let Some(filter) = &config.logging {
         env_logger::builder().parse_filters(filter).init();
     } else {
         env_logger::init();
     }
+}
 
-    let tls = if let Some(tls_path) = &config.certificate_path {
+fn tls_config(config: &DenimClientConfig) -> Result<Option<ClientConfig>, CliError> {
+    Ok(if let Some(tls_path) = &config.certificate_path {
         let _ = rustls::crypto::ring::default_provider().install_default();
-        Some(create_tls_client_config(&tls_path, None)?)
+        Some(create_tls_client_config(tls_path, None)?)
     } else {
         None
-    };
-
-    let dispatch = SamDispatchClient::new(config.dispatch_address)?;
-
-    while !dispatch.health().await {
-        info!("Dispatcher unavailable, trying again in 200ms...");
-        tokio::time::sleep(Duration::from_millis(200)).await
-    }
-    info!("Dispatcher ready!");
-    let client_info = dispatch.get_client().await?;
+    })
+}
 
-    let health = HealthClient::new(config.address.clone(), tls.clone())?;
+async fn wait_for_server(
+    config: &DenimClientConfig,
+    tls: Option<ClientConfig>,
+) -> Result<(), CliError> {
+    let health = HealthClient::new(config.address.clone(), tls)?;
 
     loop {
         let check = match health.health().await {
@@ -95,59 +496,52 @@ async fn cli() -> Result<(), CliError> {
     }
 
     info!("SAM ready!");
+    Ok(())
+}
 
-    let client = match client_info.client_type {
-        data::ClientType::Denim => {
-            TestClient::new_denim()
-                .address(config.address)
-                .buffer_size(
-                    config
-                        .channel_buffer_size
-                        .unwrap_or(DEFAULT_CHANNEL_BUFFER_SIZE),
-                )
-                .maybe_tls(tls)
-                .username(client_info.username.clone())
-                .upload_count(client_info.friends.len() + 1)
-                .inmemory(config.inmemory)
-                .call()
-                .await?
-        }
-        data::ClientType::Sam => {
-            TestClient::new_sam()
-                .address(config.address)
-                .buffer_size(
-                    config
-                        .channel_buffer_size
-                        .unwrap_or(DEFAULT_CHANNEL_BUFFER_SIZE),
-                )
-                .maybe_tls(tls)
-                .username(client_info.username.clone())
-                .upload_count(client_info.friends.len() + 1)
-                .inmemory(config.inmemory)
-                .call()
-                .await?
-        }
+async fn fault_proxy(config: &DenimClientConfig) -> Result<Option<FaultProxy>, CliError> {
+    Ok(match config.fault_profile {
+        Some(profile) => Some(FaultProxy::start(config.address.clone(), profile).await?),
+        None => None,
+    })
+}
+
+fn client_settings(
+    config: &DenimClientConfig,
+    client_info: &ClientInfo,
+    tls: Option<ClientConfig>,
+    proxy: &Option<FaultProxy>,
+) -> Result<ClientSettings, CliError> {
+    let denim = match client_info.client_type {
+        data::ClientType::Denim => true,
+        data::ClientType::Sam => false,
         data::ClientType::Other => Err(CliError::UnknownClientType)?,
     };
+    if !config.inmemory {
+        storage::check_username(&client_info.username)?;
+    }
+    Ok(ClientSettings {
+        denim,
+        address: match proxy {
+            Some(proxy) => proxy.address(),
+            None => config.address.clone(),
+        },
+        username: client_info.username.clone(),
+        buffer_size: config
+            .channel_buffer_size
+            .unwrap_or(DEFAULT_CHANNEL_BUFFER_SIZE),
+        tls,
+        upload_count: client_info.friends.len() + 1,
+        inmemory: config.inmemory,
+        stores: Stores::new(config.storage.as_ref(), &client_info.username),
+    })
+}
 
-    dispatch
-        .upload_account_id(
-            AccountInfo::builder()
-                .account_id(client.account_id())
-                .build(),
-        )
-        .await?;
-
-    let start_info = dispatch.sync().await?;
-    let dispatch_data = DispatchData::new(client_info, start_info);
-
-    let runner = ScenarioRunner::new(dispatch_data, client);
-    info!("Starting Scenario...");
-    let report = runner.start().await;
-
-    dispatch.upload_results(report).await?;
-
-    Ok(())
+/// Applies the store lifecycle once the run is over, a failure only costs disk space.
+fn finish_stores(stores: &Stores, start_time: u128) {
+    if let Err(e) = stores.finish(start_time) {
+        error!("Failed to clean up stores: {e}");
+    }
 }
 
 #[tokio::main]
@@ -0,0 +1,340 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    Json, Router,
+    extract::State,
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use derive_more::{Display, Error, From};
+use log::{error, info};
+use sam_common::AccountId;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::TcpListener,
+    sync::{Mutex, watch},
+};
+
+use crate::{
+    data::{AccountInfo, ClientInfo, ClientReport, LogBatch, MessageLog, StartInfo},
+    delivery::{reconcile, reconcile_restarts},
+};
+
+const SESSION_COOKIE: &str = "session";
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+#[derive(Debug, Display, Error, From)]
+pub enum MockDispatchError {
+    Io(std::io::Error),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Population {
+    pub clients: Vec<ClientInfo>,
+}
+
+struct DispatchState {
+    clients: Vec<ClientInfo>,
+    assigned: usize,
+    account_ids: HashMap<String, AccountId>,
+    uploaded: HashSet<usize>,
+    idempotency_keys: HashSet<String>,
+    batches: HashMap<usize, BTreeMap<u64, Vec<MessageLog>>>,
+    reports: HashMap<String, ClientReport>,
+    output: PathBuf,
+    ready: watch::Sender<bool>,
+    done: watch::Sender<bool>,
+}
+
+type SharedState = Arc<Mutex<DispatchState>>;
+
+/// Serves the dispatcher protocol used by `SamDispatchClient` until every client in the
+/// population has uploaded its report.
+pub async fn serve(
+    population: Population,
+    address: &str,
+    output: PathBuf,
+) -> Result<(), MockDispatchError> {
+    std::fs::create_dir_all(&output)?;
+
+    let (ready, _) = watch::channel(false);
+    let (done, mut done_recv) = watch::channel(false);
+    let state = Arc::new(Mutex::new(DispatchState {
+        clients: population.clients,
+        assigned: 0,
+        account_ids: HashMap::new(),
+        uploaded: HashSet::new(),
+        idempotency_keys: HashSet::new(),
+        batches: HashMap::new(),
+        reports: HashMap::new(),
+        output,
+        ready,
+        done,
+    }));
+
+    let app = Router::new()
+        .route("/health", get(health))
+        .route("/client", get(client))
+        .route("/id", post(id))
+        .route("/sync", get(sync))
+        .route("/upload", post(upload))
+        .route("/upload/batch", post(upload_batch))
+        .with_state(state);
+
+    let listener = TcpListener::bind(address).await?;
+    info!("Mock dispatcher listening on '{address}'");
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            let _ = done_recv.wait_for(|done| *done).await;
+            info!("All reports received, shutting down...");
+        })
+        .await?;
+    Ok(())
+}
+
+fn session(headers: &HeaderMap) -> Option<usize> {
+    headers
+        .get(header::COOKIE)?
+        .to_str()
+        .ok()?
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+async fn health() -> StatusCode {
+    StatusCode::OK
+}
+
+async fn client(State(state): State<SharedState>) -> Response {
+    let mut guard = state.lock().await;
+    let index = guard.assigned;
+    let info = match guard.clients.get(index) {
+        Some(info) => info.clone(),
+        None => {
+            error!("Client requested, but all clients have been assigned");
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
+    guard.assigned += 1;
+    info!(
+        "Assigned '{}' ({}/{})",
+        info.username,
+        index + 1,
+        guard.clients.len()
+    );
+
+    (
+        [(header::SET_COOKIE, format!("{SESSION_COOKIE}={index}"))],
+        Json(info),
+    )
+        .into_response()
+}
+
+async fn id(State(state): State<SharedState>, headers: HeaderMap, body: String) -> StatusCode {
+    let Some(index) = session(&headers) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    let account_info: AccountInfo = match serde_json::from_str(&body) {
+        Ok(info) => info,
+        Err(e) => {
+            error!("Invalid account info: {e}");
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+
+    let mut guard = state.lock().await;
+    let Some(username) = guard.clients.get(index).map(|c| c.username.clone()) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    guard
+        .account_ids
+        .insert(username.clone(), account_info.account_id);
+    info!("Received account id for '{username}'");
+
+    if guard.account_ids.len() == guard.clients.len() {
+        info!("All clients registered, releasing sync");
+        guard.ready.send_replace(true);
+    }
+    StatusCode::OK
+}
+
+async fn sync(State(state): State<SharedState>, headers: HeaderMap) -> Response {
+    let Some(index) = session(&headers) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let mut ready = {
+        let guard = state.lock().await;
+        if index >= guard.clients.len() {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        guard.ready.subscribe()
+    };
+    if ready.wait_for(|ready| *ready).await.is_err() {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    let guard = state.lock().await;
+    let client = &guard.clients[index];
+    // a group's sender needs the account ids of its members to send them copies, and the
+    // members need the sender's to accept them, whether or not they are friends
+    let members = client.groups.values().flat_map(|group| &group.members);
+    let senders = guard
+        .clients
+        .iter()
+        .filter(|other| {
+            other
+                .groups
+                .values()
+                .any(|group| group.members.contains(&client.username))
+        })
+        .map(|other| &other.username);
+    let names: HashSet<&String> = client
+        .friends
+        .keys()
+        .chain(members)
+        .chain(senders)
+        .filter(|name| **name != client.username)
+        .collect();
+    let friends = names
+        .into_iter()
+        .filter_map(|name| {
+            guard
+                .account_ids
+                .get(name)
+                .map(|account_id| (name.clone(), *account_id))
+        })
+        .collect();
+    Json(StartInfo { friends }).into_response()
+}
+
+/// Rewrites every report once the messages lost between each pair of clients are known.
+fn reconcile_reports(state: &mut DispatchState) {
+    reconcile(&mut state.reports);
+    reconcile_restarts(&mut state.reports);
+    for (username, report) in &state.reports {
+        let path = state.output.join(format!("{username}.json"));
+        if let Err(e) = write_json(&path, report) {
+            error!("Failed to write reconciled report for '{username}': {e}");
+        }
+    }
+    info!("Reconciled delivery across {} reports", state.reports.len());
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), serde_json::Error> {
+    let file = std::fs::File::create(path).map_err(serde_json::Error::io)?;
+    serde_json::to_writer_pretty(file, value)
+}
+
+async fn upload_batch(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    body: String,
+) -> StatusCode {
+    let Some(index) = session(&headers) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    let batch: LogBatch = match serde_json::from_str(&body) {
+        Ok(batch) => batch,
+        Err(e) => {
+            error!("Invalid log batch: {e}");
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+
+    let mut guard = state.lock().await;
+    let Some(username) = guard.clients.get(index).map(|c| c.username.clone()) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    let path = guard
+        .output
+        .join(format!("{username}.batch-{}.json", batch.sequence));
+    let batches = guard.batches.entry(index).or_default();
+    if batches.contains_key(&batch.sequence) {
+        info!(
+            "Ignoring duplicate batch {} from '{username}'",
+            batch.sequence
+        );
+        return StatusCode::OK;
+    }
+    if let Err(e) = write_json(&path, &batch) {
+        error!("Failed to write batch for '{username}': {e}");
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    info!("Received batch {} from '{username}'", batch.sequence);
+    batches.insert(batch.sequence, batch.messages);
+    StatusCode::OK
+}
+
+async fn upload(State(state): State<SharedState>, headers: HeaderMap, body: String) -> StatusCode {
+    let Some(index) = session(&headers) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    let mut report: ClientReport = match serde_json::from_str(&body) {
+        Ok(report) => report,
+        Err(e) => {
+            error!("Invalid client report: {e}");
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+
+    let mut guard = state.lock().await;
+    let Some(username) = guard.clients.get(index).map(|c| c.username.clone()) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    let idempotency_key = headers
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|key| key.to_str().ok())
+        .map(str::to_string);
+    if idempotency_key
+        .as_ref()
+        .is_some_and(|key| guard.idempotency_keys.contains(key))
+    {
+        info!("Ignoring repeated report upload from '{username}'");
+        return StatusCode::OK;
+    }
+    if let Some(expected) = report.batches {
+        let mut batches = guard.batches.get(&index).cloned().unwrap_or_default();
+        // a batch may have arrived even though the client saw its upload fail
+        for batch in std::mem::take(&mut report.unsent_batches) {
+            batches.entry(batch.sequence).or_insert(batch.messages);
+        }
+        if batches.len() as u64 != expected {
+            error!(
+                "Report from '{username}' expected {expected} batches, but {} were received",
+                batches.len()
+            );
+        }
+        report.messages = batches.into_values().flatten().collect();
+    }
+
+    let path = guard.output.join(format!("{username}.json"));
+    if let Err(e) = write_json(&path, &report) {
+        error!("Failed to write report for '{username}': {e}");
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    guard.uploaded.insert(index);
+    guard.reports.insert(username.clone(), report);
+    if let Some(key) = idempotency_key {
+        guard.idempotency_keys.insert(key);
+    }
+    info!(
+        "Received report from '{username}' ({}/{})",
+        guard.uploaded.len(),
+        guard.clients.len()
+    );
+
+    if guard.uploaded.len() == guard.clients.len() {
+        reconcile_reports(&mut guard);
+        guard.done.send_replace(true);
+    }
+    StatusCode::OK
+}
@@ -0,0 +1,340 @@
+use std::string::FromUtf8Error;
+
+use derive_more::{Display, Error, From};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Size of the length prefix in front of every string in the header.
+const STRING_LENGTH: usize = 1;
+/// Size of the fixed fields following the sender: the run start, counter, send time and
+/// sequence as big endian u64 and a byte of flags announcing the optional fields.
+const FIXED_FIELDS: usize = 4 * 8 + 1;
+/// Size of the big endian body length following the header.
+const BODY_LENGTH: usize = 4;
+/// Size of the SHA-256 digest of the body following the body length.
+const DIGEST: usize = 32;
+/// Flag set when the header carries `reply_to`.
+const REPLY_TO: u8 = 1;
+/// Flag set when the header carries `group`.
+const GROUP: u8 = 2;
+
+#[derive(Debug, Display, Error, From)]
+pub enum PayloadError {
+    Truncated,
+    /// A string in the header is longer than its one byte length prefix allows
+    FieldTooLong,
+    BodyTooLarge,
+    UnknownFlags,
+    Utf8(FromUtf8Error),
+}
+
+/// Metadata embedded at the start of every message payload sent by the scenario, encoded in
+/// a fixed binary layout so even small messages keep most of their size for the body.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PayloadHeader {
+    pub sender: String,
+    /// Start time of the sender's run, telling apart the counters of different runs
+    pub run_start: u128,
+    /// Number of messages the sender sent before this one in its run
+    pub counter: u64,
+    pub send_time: u128,
+    pub reply_to: Option<String>,
+    /// Name of the group for messages fanned out to a group
+    pub group: Option<String>,
+    pub sequence: u64,
+}
+
+/// Message ids are unique across the population and across runs as long as usernames are,
+/// even when accounts are reused or spooled reports are uploaded again.
+pub fn message_id(sender: &str, run_start: u128, counter: u64) -> String {
+    format!("{sender}#{run_start}#{counter}")
+}
+
+/// Result of checking a received body against the length and digest it was sent with.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Integrity {
+    Intact,
+    Truncated,
+    Corrupted,
+}
+
+impl From<&PayloadError> for Integrity {
+    fn from(err: &PayloadError) -> Self {
+        match err {
+            PayloadError::Truncated => Integrity::Truncated,
+            _ => Integrity::Corrupted,
+        }
+    }
+}
+
+impl PayloadHeader {
+    pub fn id(&self) -> String {
+        message_id(&self.sender, self.run_start, self.counter)
+    }
+
+    /// Number of bytes the encoded header adds in front of the body.
+    pub fn overhead(&self) -> usize {
+        let optional: usize = [&self.reply_to, &self.group]
+            .into_iter()
+            .flatten()
+            .map(|field| STRING_LENGTH + field.len())
+            .sum();
+        STRING_LENGTH + self.sender.len() + FIXED_FIELDS + optional + BODY_LENGTH + DIGEST
+    }
+
+    /// Encodes the length prefixed sender, the fixed fields and the flagged optional fields,
+    /// followed by the length and digest of `body` and the body itself.
+    pub fn encode(&self, body: &[u8]) -> Result<Vec<u8>, PayloadError> {
+        let body_length = u32::try_from(body.len()).map_err(|_| PayloadError::BodyTooLarge)?;
+        let mut bytes = Vec::with_capacity(self.overhead() + body.len());
+        put_string(&mut bytes, &self.sender)?;
+        // milliseconds since the epoch fit a u64 for a few hundred million years
+        bytes.extend_from_slice(&(self.run_start as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.counter.to_be_bytes());
+        bytes.extend_from_slice(&(self.send_time as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        let flags = [(REPLY_TO, &self.reply_to), (GROUP, &self.group)]
+            .into_iter()
+            .filter(|(_, field)| field.is_some())
+            .fold(0, |flags, (flag, _)| flags | flag);
+        bytes.push(flags);
+        for field in [&self.reply_to, &self.group].into_iter().flatten() {
+            put_string(&mut bytes, field)?;
+        }
+        bytes.extend_from_slice(&body_length.to_be_bytes());
+        bytes.extend(Sha256::digest(body));
+        bytes.extend_from_slice(body);
+        Ok(bytes)
+    }
+
+    /// Decodes the header of a payload and verifies its body.
+    pub fn decode(payload: &[u8]) -> Result<(Self, Integrity), PayloadError> {
+        let mut rest = payload;
+        let sender = take_string(&mut rest)?;
+        let run_start = take_u64(&mut rest)? as u128;
+        let counter = take_u64(&mut rest)?;
+        let send_time = take_u64(&mut rest)? as u128;
+        let sequence = take_u64(&mut rest)?;
+        let [flags] = take::<1>(&mut rest)?;
+        if flags & !(REPLY_TO | GROUP) != 0 {
+            return Err(PayloadError::UnknownFlags);
+        }
+        let reply_to = (flags & REPLY_TO != 0)
+            .then(|| take_string(&mut rest))
+            .transpose()?;
+        let group = (flags & GROUP != 0)
+            .then(|| take_string(&mut rest))
+            .transpose()?;
+        let header = Self {
+            sender,
+            run_start,
+            counter,
+            send_time,
+            reply_to,
+            group,
+            sequence,
+        };
+
+        let Some((body_length, rest)) = rest.split_first_chunk::<BODY_LENGTH>() else {
+            return Ok((header, Integrity::Truncated));
+        };
+        let Some((digest, body)) = rest.split_first_chunk::<DIGEST>() else {
+            return Ok((header, Integrity::Truncated));
+        };
+
+        let body_length = u32::from_be_bytes(*body_length) as usize;
+        let integrity = if body.len() < body_length {
+            Integrity::Truncated
+        } else if body.len() > body_length || Sha256::digest(body).as_slice() != digest {
+            Integrity::Corrupted
+        } else {
+            Integrity::Intact
+        };
+        Ok((header, integrity))
+    }
+}
+
+fn put_string(bytes: &mut Vec<u8>, field: &str) -> Result<(), PayloadError> {
+    let length = u8::try_from(field.len()).map_err(|_| PayloadError::FieldTooLong)?;
+    bytes.push(length);
+    bytes.extend_from_slice(field.as_bytes());
+    Ok(())
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], PayloadError> {
+    let (head, rest) = bytes
+        .split_first_chunk::<N>()
+        .ok_or(PayloadError::Truncated)?;
+    *bytes = rest;
+    Ok(*head)
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Result<u64, PayloadError> {
+    take::<8>(bytes).map(u64::from_be_bytes)
+}
+
+fn take_string(bytes: &mut &[u8]) -> Result<String, PayloadError> {
+    let [length] = take::<1>(bytes)?;
+    if bytes.len() < length as usize {
+        return Err(PayloadError::Truncated);
+    }
+    let (field, rest) = bytes.split_at(length as usize);
+    *bytes = rest;
+    Ok(String::from_utf8(field.to_vec())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> PayloadHeader {
+        PayloadHeader {
+            sender: "alice".to_string(),
+            run_start: 1_600_000_000_000,
+            counter: 3,
+            send_time: 1_700_000_000_000,
+            reply_to: Some(message_id("bob", 1_600_000_000_000, 1)),
+            group: None,
+            sequence: 7,
+        }
+    }
+
+    fn minimal() -> PayloadHeader {
+        PayloadHeader {
+            reply_to: None,
+            ..header()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let body = b"hello world";
+        let payload = header().encode(body).unwrap();
+        assert_eq!(payload.len(), header().overhead() + body.len());
+
+        let (decoded, integrity) = PayloadHeader::decode(&payload).unwrap();
+        assert_eq!(decoded, header());
+        assert_eq!(integrity, Integrity::Intact);
+    }
+
+    #[test]
+    fn round_trip_optional_fields() {
+        let headers = [
+            minimal(),
+            PayloadHeader {
+                group: Some("friends".to_string()),
+                ..minimal()
+            },
+            PayloadHeader {
+                group: Some("friends".to_string()),
+                ..header()
+            },
+        ];
+        for header in headers {
+            let payload = header.encode(b"body").unwrap();
+            assert_eq!(payload.len(), header.overhead() + 4);
+            assert_eq!(PayloadHeader::decode(&payload).unwrap().0, header);
+        }
+    }
+
+    #[test]
+    fn small_overhead() {
+        // the minimum the README promises: 70 bytes plus the sender's name
+        assert_eq!(minimal().overhead(), 70 + "alice".len());
+        assert_eq!(minimal().encode(&[]).unwrap().len(), 75);
+    }
+
+    #[test]
+    fn ids_differ_between_runs() {
+        let next_run = PayloadHeader {
+            run_start: 1_600_000_100_000,
+            ..minimal()
+        };
+        assert_eq!(minimal().id(), "alice#1600000000000#3");
+        assert_ne!(minimal().id(), next_run.id());
+        let (decoded, _) = PayloadHeader::decode(&next_run.encode(&[]).unwrap()).unwrap();
+        assert_eq!(decoded.id(), next_run.id());
+    }
+
+    #[test]
+    fn long_fields_are_rejected() {
+        let header = PayloadHeader {
+            group: Some("x".repeat(256)),
+            ..header()
+        };
+        assert!(matches!(
+            header.encode(&[]),
+            Err(PayloadError::FieldTooLong)
+        ));
+    }
+
+    #[test]
+    fn round_trip_empty_body() {
+        let payload = header().encode(&[]).unwrap();
+        let (decoded, integrity) = PayloadHeader::decode(&payload).unwrap();
+        assert_eq!(decoded, header());
+        assert_eq!(integrity, Integrity::Intact);
+    }
+
+    #[test]
+    fn truncated_body() {
+        let payload = header().encode(b"hello world").unwrap();
+        let (decoded, integrity) = PayloadHeader::decode(&payload[..payload.len() - 1]).unwrap();
+        assert_eq!(decoded, header());
+        assert_eq!(integrity, Integrity::Truncated);
+    }
+
+    #[test]
+    fn truncated_digest() {
+        let payload = header().encode(b"hello world").unwrap();
+        let end = header().overhead() - 1;
+        let (_, integrity) = PayloadHeader::decode(&payload[..end]).unwrap();
+        assert_eq!(integrity, Integrity::Truncated);
+    }
+
+    #[test]
+    fn truncated_header() {
+        let payload = header().encode(b"hello world").unwrap();
+        let err = PayloadHeader::decode(&payload[..10]).unwrap_err();
+        assert!(matches!(err, PayloadError::Truncated));
+        assert_eq!(Integrity::from(&err), Integrity::Truncated);
+        assert!(matches!(
+            PayloadHeader::decode(&payload[..1]),
+            Err(PayloadError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn corrupted_body() {
+        let mut payload = header().encode(b"hello world").unwrap();
+        let last = payload.len() - 1;
+        payload[last] ^= 0xff;
+        let (_, integrity) = PayloadHeader::decode(&payload).unwrap();
+        assert_eq!(integrity, Integrity::Corrupted);
+    }
+
+    #[test]
+    fn extra_bytes_are_corruption() {
+        let mut payload = header().encode(b"hello world").unwrap();
+        payload.push(0);
+        let (_, integrity) = PayloadHeader::decode(&payload).unwrap();
+        assert_eq!(integrity, Integrity::Corrupted);
+    }
+
+    #[test]
+    fn corrupted_header() {
+        let mut payload = header().encode(b"hello world").unwrap();
+        // the flags follow the sender and the fixed fields
+        payload[STRING_LENGTH + "alice".len() + FIXED_FIELDS - 1] = 0x80;
+        let err = PayloadHeader::decode(&payload).unwrap_err();
+        assert!(matches!(err, PayloadError::UnknownFlags));
+        assert_eq!(Integrity::from(&err), Integrity::Corrupted);
+
+        let mut payload = header().encode(b"hello world").unwrap();
+        payload[STRING_LENGTH] = 0xff;
+        let err = PayloadHeader::decode(&payload).unwrap_err();
+        assert!(matches!(err, PayloadError::Utf8(_)));
+        assert_eq!(Integrity::from(&err), Integrity::Corrupted);
+    }
+}
@@ -1,79 +1,281 @@
-use std::{
-    collections::HashMap,
-    rc::Rc,
-    sync::Arc,
-    time::{Duration, SystemTime, UNIX_EPOCH},
-};
+use std::{cell::Cell, collections::HashMap, rc::Rc, sync::Arc, time::Duration};
 
 use bon::builder;
+use derive_more::{Display, Error, From};
 use log::{error, info, warn};
-use rand::{distributions::WeightedIndex, prelude::Distribution, thread_rng};
+use rand::{Rng, SeedableRng, distributions::WeightedIndex, prelude::Distribution, thread_rng};
+use rand_chacha::ChaCha8Rng;
 use sam_client::encryption::DecryptedEnvelope;
 use sam_common::AccountId;
 use tokio::{
-    sync::{Mutex, broadcast::Receiver},
+    sync::{
+        Mutex,
+        broadcast::{
+            Receiver,
+            error::{RecvError, TryRecvError},
+        },
+        mpsc::UnboundedSender,
+        watch,
+    },
     task::LocalSet,
 };
 
 use crate::{
-    data::{ClientReport, DispatchData, Friend, MessageLog, MessageType},
-    test_client::TestClient,
+    churn::{ChurnSchedule, Transition, queued_summary},
+    content::{ContentError, ContentGenerator},
+    conversation::{Conversations, Recipient, count_received},
+    data::{
+        ClientReport, ConversationLog, DispatchData, FaultLog, Friend, Group, GroupDelivery,
+        GroupMessageLog, LogBatch, MessageLog, MessageType, OfflinePeriod, ReconnectEvent,
+        RestartLog, TickLateness,
+    },
+    delivery::{Checkpoint, Delivery, DeliveryTracker},
+    fault::ArcFaults,
+    payload::{Integrity, PayloadHeader},
+    size::{MessageSizes, SizeError},
+    stream::{LogDigest, LogStream},
+    test_client::{ClientSettings, TestClient},
     timer::Timer,
-    utils::{denim_friends, get_friend, normal_friends, random_bytes, sample_prob, usernames},
+    traffic::{RateAccumulator, SendScheduler, TrafficProfile},
+    utils::{
+        denim_friends, get_group, normal_friends, now_millis, random_delay, random_payload,
+        sample_prob, usernames,
+    },
 };
 
-type ArcClient = Arc<Mutex<TestClient>>;
+/// Empty once a restart failed, as the old client is dropped before the new one is created.
+type ArcClient = Arc<Mutex<Option<TestClient>>>;
 type ArcLogs = Arc<Mutex<Vec<MessageLog>>>;
 type ArcBool = Arc<Mutex<bool>>;
+type ArcDelivery = Arc<Mutex<DeliveryTracker>>;
 type ArcIncoming = Arc<Mutex<Vec<ReplyType>>>;
+type RcMessageCounter = Rc<Cell<u64>>;
+type ArcStream = Arc<Mutex<LogStream>>;
+type ArcTick = Arc<Mutex<Option<u32>>>;
+type ArcLateness = Arc<Mutex<Vec<TickLateness>>>;
+type ArcConversations = Arc<Mutex<Vec<ConversationLog>>>;
+type ArcGroups = Arc<Mutex<Vec<GroupMessageLog>>>;
+type ArcOffline = Arc<Mutex<Vec<OfflinePeriod>>>;
+type ArcReconnects = Arc<Mutex<Vec<ReconnectEvent>>>;
+type RcFlag = Rc<Cell<bool>>;
+type ArcRestarts = Arc<Mutex<Vec<(RestartLog, Checkpoint)>>>;
+type ScenarioRng = ChaCha8Rng;
+
+const RECONNECT_ATTEMPTS: u32 = 8;
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Display, Error, From)]
+pub enum ScenarioError {
+    Size(SizeError),
+    Content(ContentError),
+    /// Name of a `(min, max)` range in the `ClientInfo` whose `min` is above its `max`
+    InvalidRange(#[error(not(source))] &'static str),
+}
 
 pub struct ScenarioRunner {
     data: DispatchData,
     client: ArcClient,
-    local_set: LocalSet,
     start_time: u128,
     message_logs: ArcLogs,
+    delivery: ArcDelivery,
+    log_stream: Option<ArcStream>,
+    shutdown: Option<watch::Receiver<bool>>,
+    interrupted: ArcTick,
+    tick_lateness: ArcLateness,
+    conversations: ArcConversations,
+    group_messages: ArcGroups,
+    offline_periods: ArcOffline,
+    reconnects: ArcReconnects,
+    faults: Option<ArcFaults>,
+    restart: Option<ClientSettings>,
+    restarts: ArcRestarts,
+    message_sizes: Arc<MessageSizes>,
+    content: Arc<ContentGenerator>,
     stop: ArcBool,
+    seed: u64,
 }
 
 impl ScenarioRunner {
-    pub fn new(data: DispatchData, client: TestClient) -> Self {
-        Self {
+    pub fn new(data: DispatchData, client: TestClient) -> Result<Self, ScenarioError> {
+        if let Some(conversation) = &data.client.conversation {
+            check_range("conversation.messageRange", conversation.message_range)?;
+            check_range(
+                "conversation.thinkTicksRange",
+                conversation.think_ticks_range,
+            )?;
+        }
+        check_range("messageSizeRange", data.client.message_size_range)?;
+        let seed = data.client.seed.unwrap_or_else(|| thread_rng().r#gen());
+        let message_sizes = MessageSizes::new(
+            &data.client.message_size_distribution,
+            data.client.message_size_range,
+        )?;
+        let content = ContentGenerator::new(&data.client.payload_content)?;
+        Ok(Self {
             data,
-            client: Arc::new(Mutex::new(client)),
-            local_set: LocalSet::new(),
+            client: Arc::new(Mutex::new(Some(client))),
             start_time: 0,
             message_logs: ArcLogs::default(),
+            delivery: ArcDelivery::default(),
+            log_stream: None,
+            shutdown: None,
+            interrupted: ArcTick::default(),
+            tick_lateness: ArcLateness::default(),
+            conversations: ArcConversations::default(),
+            group_messages: ArcGroups::default(),
+            offline_periods: ArcOffline::default(),
+            reconnects: ArcReconnects::default(),
+            faults: None,
+            restart: None,
+            restarts: ArcRestarts::default(),
+            message_sizes: Arc::new(message_sizes),
+            content: Arc::new(content),
             stop: Arc::new(Mutex::new(false)),
-        }
+            seed,
+        })
+    }
+
+    /// Periodically hands new message logs to `batches` instead of only returning them in the report.
+    pub fn stream_logs(mut self, batches: UnboundedSender<LogBatch>, flush_ticks: u32) -> Self {
+        self.log_stream = Some(Arc::new(Mutex::new(LogStream::new(batches, flush_ticks))));
+        self
+    }
+
+    /// Reports the faults injected into the client's connections.
+    pub fn inject_faults(mut self, faults: ArcFaults) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Recreates the client with `settings` at the restart ticks of the scenario.
+    pub fn restart_from(mut self, settings: ClientSettings) -> Self {
+        self.restart = Some(settings);
+        self
     }
 
+    /// Ends the scenario early with a partial report once `shutdown` becomes true.
+    pub fn shutdown_on(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Runs the scenario on a `LocalSet` of its own, so the returned future is not `Send`,
+    /// while the runner itself can still be moved to the thread that drives it.
     pub async fn start(mut self) -> ClientReport {
-        self.start_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("time cannot go backwards")
-            .as_millis();
-        self.event_loop().await;
-        self.local_set.await;
-        if let Err(e) = self.client.lock().await.disconnect().await {
+        info!(
+            "Using seed {} for '{}'",
+            self.seed, self.data.client.username
+        );
+        // actions get their own rng drawn in tick order, so task scheduling cannot reorder draws
+        let mut rng = ScenarioRng::seed_from_u64(self.seed);
+        // the whole schedule is shifted, so ticks stay aligned with `start_time`
+        let phase_offset = random_delay(self.data.client.max_phase_offset_millis, &mut rng);
+        let mut shutdown = self.shutdown.clone();
+        tokio::select! {
+            _ = tokio::time::sleep(phase_offset) => {}
+            // the event loop sees the shutdown as well and ends the run right away
+            _ = shutdown_requested(&mut shutdown) => {}
+        }
+
+        self.start_time = now_millis();
+        let local_set = LocalSet::new();
+        self.event_loop(&local_set, rng).await;
+        local_set.await;
+        let mut offline_periods = std::mem::take(&mut *self.offline_periods.lock().await);
+        let offline = offline_periods
+            .last()
+            .is_some_and(|period| period.end_tick.is_none());
+        // a client that ended the run offline is already disconnected
+        let res = match self.client.lock().await.as_mut() {
+            Some(client) if !offline => client.disconnect().await,
+            _ => Ok(()),
+        };
+        if let Err(e) = res {
             error!("Failed to disconnect: {e}");
         };
+        let messages = std::mem::take(&mut *self.message_logs.lock().await);
+        let (messages, batches, digest) = match &self.log_stream {
+            Some(stream) => {
+                let mut stream = stream.lock().await;
+                stream.flush(messages);
+                (Vec::new(), Some(stream.batches()), stream.take_digest())
+            }
+            None => {
+                let mut digest = LogDigest::new();
+                digest.add(&messages);
+                (messages, None, digest)
+            }
+        };
+        let restarts = restart_summary(
+            std::mem::take(&mut *self.restarts.lock().await),
+            &*self.delivery.lock().await,
+        );
+        queued_summary(&mut offline_periods, &digest.received);
+        let mut conversations = std::mem::take(&mut *self.conversations.lock().await);
+        count_received(&mut conversations, &digest.received);
+        let interrupted_tick = *self.interrupted.lock().await;
         ClientReport {
             start_time: self.start_time,
-            messages: self.message_logs.lock().await.clone(),
+            seed: self.seed,
+            phase_offset_millis: phase_offset.as_millis() as u64,
+            partial: interrupted_tick.is_some(),
+            interrupted_tick,
+            tick_lateness: std::mem::take(&mut *self.tick_lateness.lock().await),
+            messages,
+            batches,
+            unsent_batches: Vec::new(),
+            delivery: self.delivery.lock().await.summary(),
+            sent: self.delivery.lock().await.sent_summary(),
+            integrity: digest.integrity,
+            conversations,
+            group_messages: std::mem::take(&mut *self.group_messages.lock().await),
+            offline_periods,
+            reconnects: std::mem::take(&mut *self.reconnects.lock().await),
+            faults: match &self.faults {
+                Some(faults) => {
+                    fault_logs(faults, self.start_time, self.data.client.tick_millis).await
+                }
+                None => Vec::new(),
+            },
+            restarts,
         }
     }
 
-    async fn event_loop(&self) {
+    async fn event_loop(&self, local_set: &LocalSet, mut rng: ScenarioRng) {
         let tick_time = self.data.client.tick_millis;
         let end_tick = self.data.client.duration_ticks;
-        let send_rate = self.data.client.send_rate;
-        let reply_rate = self.data.client.reply_rate;
+        let mut send_scheduler =
+            SendScheduler::new(self.data.client.send_process, self.data.client.send_rate);
+        let mut replies = RateAccumulator::new(self.data.client.reply_rate);
         let client = self.client.clone();
         let msg_log = self.message_logs.clone();
+        let delivery = self.delivery.clone();
+        let log_stream = self.log_stream.clone();
+        let mut shutdown = self.shutdown.clone();
+        let interrupted = self.interrupted.clone();
+        let tick_lateness = self.tick_lateness.clone();
+        let conversation_logs = self.conversations.clone();
+        let group_logs = self.group_messages.clone();
+        let offline_logs = self.offline_periods.clone();
+        let reconnects = self.reconnects.clone();
+        let reconnecting = RcFlag::default();
+        let reconnect_shutdown = self.shutdown.clone();
+        let restart = self.restart.clone();
+        let restarts = self.restarts.clone();
+        let restart_ticks = self.data.client.restart_ticks.clone();
+        let mut churn = self.data.client.churn.clone().map(ChurnSchedule::new);
+        let groups = Rc::new(self.data.client.groups.clone());
+        let mut group_sends = RateAccumulator::new(self.data.client.group_send_rate.unwrap_or(0));
+        let mut conversations = self.data.client.conversation.map(Conversations::new);
+        let missed_tick_policy = self.data.client.missed_tick_policy;
         let friends = &self.data.client.friends;
 
-        let (normal_friends, denim_friends) = if client.lock().await.is_denim() {
+        let (normal_friends, denim_friends) = if client
+            .lock()
+            .await
+            .as_ref()
+            .is_some_and(TestClient::is_denim)
+        {
             let normal_friends = Rc::new(normal_friends(friends));
             let denim_friends = Rc::new(denim_friends(friends));
             (normal_friends, denim_friends)
@@ -83,121 +285,255 @@ impl ScenarioRunner {
 
         let account_ids = Rc::new(self.data.start.friends.clone());
         let denim_prob = self.data.client.denim_probability;
+        let traffic_profile = TrafficProfile::new(self.data.client.traffic_profile.clone());
         let reply_prob = self.data.client.reply_probability;
         let stale_reply = self.data.client.stale_reply;
-        let sizes = self.data.client.message_size_range;
+        let sizes = self.message_sizes.clone();
+        let content = self.content.clone();
         let username = self.data.client.username.clone();
+        // jitter stays within a tick, so the tick an action is logged with is the tick it ran in
+        let max_jitter = self
+            .data
+            .client
+            .max_jitter_millis
+            .map(|jitter| jitter.min(tick_time.saturating_sub(1)));
+        let message_counter = RcMessageCounter::default();
+        let start_time = self.start_time;
 
         let stop = self.stop.clone();
 
         let usernames = Rc::new(usernames(&account_ids));
         let friends = Rc::new(friends.clone());
         let incoming: ArcIncoming = Arc::default();
-        let (reg_log, den_log) = {
-            let guard = client.lock().await;
-            if guard.is_denim() {
-                let (reg, den) = (guard.regular_subscribe(), guard.deniable_subscribe());
-                let reg_log = recv_logger()
-                    .recv(reg)
-                    .msg_log(msg_log.clone())
-                    .username(username.clone())
-                    .usernames(usernames.clone())
-                    .msg_type(MessageType::Regular)
-                    .start_time(self.start_time)
-                    .tick_millis(tick_time)
-                    .stop(stop.clone())
-                    .incoming(incoming.clone())
-                    .call();
-                let den_log = recv_logger()
-                    .recv(den)
-                    .msg_log(msg_log.clone())
-                    .username(username.clone())
-                    .usernames(usernames.clone())
-                    .msg_type(MessageType::Denim)
-                    .start_time(self.start_time)
-                    .tick_millis(tick_time)
-                    .stop(stop.clone())
-                    .incoming(incoming.clone())
-                    .call();
-                (reg_log, Some(den_log))
-            } else {
-                let reg = guard.regular_subscribe();
-                let reg_log = recv_logger()
-                    .recv(reg)
-                    .msg_log(msg_log.clone())
-                    .username(username.clone())
-                    .usernames(usernames.clone())
-                    .msg_type(MessageType::Regular)
-                    .start_time(self.start_time)
-                    .tick_millis(tick_time)
-                    .stop(stop.clone())
-                    .incoming(incoming.clone())
-                    .call();
-                (reg_log, None)
+        let loggers = {
+            let msg_log = msg_log.clone();
+            let delivery = delivery.clone();
+            let username = username.clone();
+            let usernames = usernames.clone();
+            let stop = stop.clone();
+            let incoming = incoming.clone();
+            // called again after a restart, as the new client comes with new channels
+            move |client: &TestClient| {
+                let logger = |recv: Receiver<DecryptedEnvelope>, msg_type: MessageType| {
+                    recv_logger()
+                        .recv(recv)
+                        .msg_log(msg_log.clone())
+                        .delivery(delivery.clone())
+                        .username(username.clone())
+                        .usernames(usernames.clone())
+                        .msg_type(msg_type)
+                        .start_time(start_time)
+                        .tick_millis(tick_time)
+                        .stop(stop.clone())
+                        .incoming(incoming.clone())
+                        .call()
+                };
+                let mut loggers = vec![logger(client.regular_subscribe(), MessageType::Regular)];
+                if client.is_denim() {
+                    loggers.push(logger(client.deniable_subscribe(), MessageType::Denim));
+                }
+                loggers
             }
         };
-
-        self.local_set.spawn_local(reg_log);
-        if let Some(logger) = den_log {
-            self.local_set.spawn_local(logger);
+        if let Some(client) = &*client.lock().await {
+            for logger in loggers(client) {
+                local_set.spawn_local(logger);
+            }
         }
 
-        self.local_set.spawn_local(async move {
-            let mut timer = Timer::new(Duration::from_millis(tick_time.into()), end_tick);
-            tokio::task::spawn_local(
-                send_message()
-                    .username(username.clone())
-                    .client(client.clone())
-                    .friends(normal_friends.clone())
-                    .denim_friends(denim_friends.clone())
-                    .account_ids(account_ids.clone())
-                    .msg_log(msg_log.clone())
-                    .denim_prob(denim_prob)
-                    .message_sizes(sizes)
-                    .current_tick(timer.current_tick())
-                    .call(),
+        local_set.spawn_local(async move {
+            let mut timer = Timer::new(
+                Duration::from_millis(tick_time.into()),
+                end_tick,
+                missed_tick_policy,
             );
-            while timer.next().await {
-                let process_client = client.clone();
-                tokio::task::spawn_local(async move {
-                    if let Err(e) = process_client.lock().await.process_messages().await {
-                        error!("Error while processing Message: {e}");
+            let send = |current_tick: u32,
+                        denim_prob: f32,
+                        recipient: Option<Recipient>,
+                        rng: &mut ScenarioRng| {
+                tokio::task::spawn_local(
+                    send_message()
+                        .username(username.clone())
+                        .run_start(start_time)
+                        .message_counter(message_counter.clone())
+                        .client(client.clone())
+                        .friends(normal_friends.clone())
+                        .denim_friends(denim_friends.clone())
+                        .account_ids(account_ids.clone())
+                        .msg_log(msg_log.clone())
+                        .delivery(delivery.clone())
+                        .denim_prob(denim_prob)
+                        .maybe_recipient(recipient)
+                        .message_sizes(sizes.clone())
+                        .content(content.clone())
+                        .current_tick(current_tick)
+                        .jitter(random_delay(max_jitter, rng))
+                        .rng(ScenarioRng::seed_from_u64(rng.r#gen()))
+                        .call(),
+                );
+            };
+            let mut send_tick = |tick: u32, rng: &mut ScenarioRng| {
+                let load = traffic_profile.load(tick);
+                let denim_prob = traffic_profile
+                    .denim_probability(tick)
+                    .unwrap_or(denim_prob);
+                let sends = send_scheduler.sends(load, rng);
+                match &mut conversations {
+                    Some(conversations) => {
+                        let open = |rng: &mut ScenarioRng| {
+                            Recipient::pick(&normal_friends, &denim_friends, denim_prob, rng)
+                        };
+                        if let Some(recipient) = conversations.next(tick, sends, open, rng) {
+                            send(tick, denim_prob, Some(recipient), rng);
+                        }
                     }
-                });
+                    None => {
+                        for _ in 0..sends {
+                            send(tick, denim_prob, None, rng);
+                        }
+                    }
+                }
+            };
 
-                if timer.do_action(reply_rate) {
+            let transition = churn
+                .as_mut()
+                .and_then(|churn| churn.next(timer.current_tick(), &mut rng));
+            if let Some(transition) = transition {
+                change_connection(&client, &username, transition).await;
+            }
+            if !churn.as_ref().is_some_and(ChurnSchedule::is_offline) {
+                send_tick(timer.current_tick(), &mut rng);
+            }
+            loop {
+                let tick = timer.current_tick();
+                let next = tokio::select! {
+                    next = timer.next() => next,
+                    _ = shutdown_requested(&mut shutdown) => {
+                        info!("Shutdown requested, stopping '{username}' after tick {tick}");
+                        *interrupted.lock().await = Some(tick);
+                        false
+                    }
+                };
+                if !next {
+                    break;
+                }
+                let load = traffic_profile.load(timer.current_tick());
+
+                if let Some(stream) = &log_stream {
+                    let mut stream = stream.lock().await;
+                    if timer.do_action(stream.flush_ticks()) {
+                        // flushed logs are dropped, only their digest is kept for the report
+                        let logs = std::mem::take(&mut *msg_log.lock().await);
+                        stream.flush(logs);
+                    }
+                }
+
+                if restart_ticks.contains(&timer.current_tick()) {
+                    let restarted = match &restart {
+                        Some(settings) => {
+                            restart_client(
+                                &client,
+                                settings,
+                                &delivery,
+                                &restarts,
+                                timer.current_tick(),
+                            )
+                            .await
+                        }
+                        None => false,
+                    };
+                    match &*client.lock().await {
+                        Some(client) if restarted => {
+                            for logger in loggers(client) {
+                                tokio::task::spawn_local(logger);
+                            }
+                        }
+                        Some(_) => {}
+                        None => {
+                            error!("'{username}' has no client left, stopping its scenario");
+                            break;
+                        }
+                    }
+                }
+
+                if let Some(churn) = &mut churn {
+                    if let Some(transition) = churn.next(timer.current_tick(), &mut rng) {
+                        change_connection(&client, &username, transition).await;
+                    }
+                    if churn.is_offline() {
+                        continue;
+                    }
+                }
+                // work spawned now would only queue up behind the reconnect attempts
+                if reconnecting.get() {
+                    continue;
+                }
+
+                tokio::task::spawn_local(
+                    process_messages()
+                        .username(username.clone())
+                        .client(client.clone())
+                        .reconnecting(reconnecting.clone())
+                        .reconnects(reconnects.clone())
+                        .stop(stop.clone())
+                        .maybe_shutdown(reconnect_shutdown.clone())
+                        .current_tick(timer.current_tick())
+                        .call(),
+                );
+
+                for _ in 0..replies.due(load) {
                     tokio::task::spawn_local(
                         reply_message()
                             .username(username.clone())
+                            .run_start(start_time)
+                            .message_counter(message_counter.clone())
                             .client(client.clone())
                             .friends(friends.clone())
                             .account_ids(account_ids.clone())
                             .msg_log(msg_log.clone())
-                            .message_sizes(sizes)
+                            .delivery(delivery.clone())
+                            .message_sizes(sizes.clone())
+                            .content(content.clone())
                             .current_tick(timer.current_tick())
+                            .jitter(random_delay(max_jitter, &mut rng))
                             .reply_prob(reply_prob)
                             .incoming(incoming.clone())
                             .stale_ticks(stale_reply)
+                            .rng(ScenarioRng::seed_from_u64(rng.r#gen()))
                             .call(),
                     );
                 }
 
-                if timer.do_action(send_rate) {
+                send_tick(timer.current_tick(), &mut rng);
+
+                for _ in 0..group_sends.due(load) {
                     tokio::task::spawn_local(
-                        send_message()
+                        group_message()
                             .username(username.clone())
+                            .run_start(start_time)
+                            .message_counter(message_counter.clone())
                             .client(client.clone())
-                            .friends(normal_friends.clone())
-                            .denim_friends(denim_friends.clone())
+                            .groups(groups.clone())
                             .account_ids(account_ids.clone())
                             .msg_log(msg_log.clone())
-                            .denim_prob(denim_prob)
-                            .message_sizes(sizes)
+                            .group_logs(group_logs.clone())
+                            .delivery(delivery.clone())
+                            .message_sizes(sizes.clone())
+                            .content(content.clone())
                             .current_tick(timer.current_tick())
+                            .jitter(random_delay(max_jitter, &mut rng))
+                            .rng(ScenarioRng::seed_from_u64(rng.r#gen()))
                             .call(),
                     );
                 }
             }
+            if let Some(churn) = churn {
+                *offline_logs.lock().await = churn.finish();
+            }
+            if let Some(conversations) = conversations {
+                *conversation_logs.lock().await = conversations.finish(timer.current_tick());
+            }
+            *tick_lateness.lock().await = timer.take_lateness();
             *stop.lock().await = true;
         });
     }
@@ -207,6 +543,7 @@ impl ScenarioRunner {
 struct IncomingMessage {
     tick: u32,
     from: String,
+    id: Option<String>,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -232,10 +569,217 @@ impl ReplyType {
     }
 }
 
+/// Processes incoming messages and reconnects with exponential backoff when the connection
+/// was lost. The client is only locked for each attempt, so the backoff does not block it.
+#[builder]
+async fn process_messages(
+    username: String,
+    client: ArcClient,
+    reconnecting: RcFlag,
+    reconnects: ArcReconnects,
+    stop: ArcBool,
+    mut shutdown: Option<watch::Receiver<bool>>,
+    current_tick: u32,
+) {
+    if reconnecting.get() {
+        return;
+    }
+    let res = match client.lock().await.as_mut() {
+        Some(client) => client.process_messages().await,
+        None => return,
+    };
+    match res {
+        Ok(()) => return,
+        Err(e) if e.is_connection_lost() => warn!("'{username}' lost its connection: {e}"),
+        Err(e) => {
+            error!("Error while processing Message: {e}");
+            return;
+        }
+    }
+    // another task may have started reconnecting while this one waited for the client
+    if reconnecting.replace(true) {
+        return;
+    }
+
+    let disconnected_at = now_millis();
+    let mut reconnected_at = None;
+    let mut attempts = 0;
+    let mut backoff = INITIAL_RECONNECT_BACKOFF;
+    loop {
+        attempts += 1;
+        let res = match client.lock().await.as_mut() {
+            Some(client) => client.reconnect().await,
+            None => break,
+        };
+        match res {
+            Ok(()) => {
+                info!("'{username}' reconnected after {attempts} attempt(s)");
+                reconnected_at = Some(now_millis());
+                break;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to reconnect '{username}' (attempt {attempts}/{RECONNECT_ATTEMPTS}): {e}"
+                )
+            }
+        }
+        if attempts == RECONNECT_ATTEMPTS {
+            break;
+        }
+        let interrupted = tokio::select! {
+            _ = tokio::time::sleep(backoff) => false,
+            _ = stopped(&stop) => true,
+            _ = shutdown_requested(&mut shutdown) => true,
+        };
+        if interrupted {
+            info!("'{username}' stops reconnecting, the scenario is over");
+            break;
+        }
+        backoff *= 2;
+    }
+    if reconnected_at.is_none() {
+        error!("Failed to reconnect '{username}'");
+    }
+    reconnecting.set(false);
+    reconnects.lock().await.push(ReconnectEvent {
+        tick: current_tick,
+        disconnected_at,
+        reconnected_at,
+        attempts,
+        duration_millis: now_millis().saturating_sub(disconnected_at),
+    });
+}
+
+/// Ranges are sampled with `gen_range`, which panics on an inverted range.
+fn check_range(name: &'static str, (min, max): (u32, u32)) -> Result<(), ScenarioError> {
+    if min > max {
+        return Err(ScenarioError::InvalidRange(name));
+    }
+    Ok(())
+}
+
+async fn fault_logs(faults: &ArcFaults, start_time: u128, tick_millis: u32) -> Vec<FaultLog> {
+    faults
+        .lock()
+        .await
+        .iter()
+        .map(|fault| FaultLog {
+            tick: (fault.time.saturating_sub(start_time) / tick_millis.max(1) as u128) as u32,
+            time: fault.time,
+            direction: fault.direction,
+            fault: fault.fault.clone(),
+        })
+        .collect()
+}
+
+/// Replaces the client with one recreated from its stores, without disconnecting the old one first.
+/// The old client is dropped before the new one opens the stores, so a failed restart leaves none.
+async fn restart_client(
+    client: &ArcClient,
+    settings: &ClientSettings,
+    delivery: &ArcDelivery,
+    restarts: &ArcRestarts,
+    current_tick: u32,
+) -> bool {
+    let username = &settings.username;
+    if settings.inmemory {
+        warn!(
+            "'{username}' has no stores to restart from, skipping the restart at tick {current_tick}"
+        );
+        return false;
+    }
+    info!("Restarting '{username}' at tick {current_tick}");
+    let mut guard = client.lock().await;
+    let checkpoint = delivery.lock().await.checkpoint();
+    let started = now_millis();
+    // like a crashed process the old client never disconnects, and it has to release its
+    // connections and stores before they are opened again
+    *guard = None;
+    let recovered = match settings.create().await {
+        Ok(restarted) => {
+            *guard = Some(restarted);
+            true
+        }
+        Err(e) => {
+            error!("Failed to restart '{username}': {e}");
+            false
+        }
+    };
+    let restart = RestartLog {
+        tick: current_tick,
+        time: started,
+        recovery_millis: now_millis().saturating_sub(started),
+        recovered,
+        gaps: 0,
+        lost: None,
+        duplicates: 0,
+    };
+    restarts.lock().await.push((restart, checkpoint));
+    recovered
+}
+
+/// Fills in the gaps and duplicates between each restart and the next one.
+fn restart_summary(
+    restarts: Vec<(RestartLog, Checkpoint)>,
+    delivery: &DeliveryTracker,
+) -> Vec<RestartLog> {
+    let checkpoints: Vec<Checkpoint> = restarts
+        .iter()
+        .map(|(_, checkpoint)| checkpoint.clone())
+        .collect();
+    restarts
+        .into_iter()
+        .enumerate()
+        .map(|(i, (mut restart, checkpoint))| {
+            (restart.gaps, restart.duplicates) =
+                delivery.between(&checkpoint, checkpoints.get(i + 1));
+            restart
+        })
+        .collect()
+}
+
+async fn change_connection(client: &ArcClient, username: &str, transition: Transition) {
+    let mut guard = client.lock().await;
+    let Some(guard) = guard.as_mut() else {
+        return;
+    };
+    let res = match transition {
+        Transition::Offline => {
+            info!("'{username}' is going offline");
+            guard.disconnect().await
+        }
+        Transition::Online => {
+            info!("'{username}' is coming back online");
+            guard.reconnect().await
+        }
+    };
+    if let Err(e) = res {
+        error!("Failed to go {transition:?} for '{username}': {e}");
+    }
+}
+
+/// Completes once the event loop has finished.
+async fn stopped(stop: &ArcBool) {
+    while !*stop.lock().await {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn shutdown_requested(shutdown: &mut Option<watch::Receiver<bool>>) {
+    let requested = match shutdown {
+        Some(shutdown) => shutdown.wait_for(|shutdown| *shutdown).await.is_ok(),
+        None => false,
+    };
+    if !requested {
+        std::future::pending().await
+    }
+}
+
 #[builder]
 async fn recv_logger(
     mut recv: Receiver<DecryptedEnvelope>,
     msg_log: ArcLogs,
+    delivery: ArcDelivery,
     username: String,
     usernames: Rc<HashMap<AccountId, String>>,
     msg_type: MessageType,
@@ -244,21 +788,33 @@ async fn recv_logger(
     incoming: ArcIncoming,
     stop: ArcBool,
 ) {
-    while !*stop.lock().await {
-        let timeout_res = tokio::time::timeout(Duration::from_millis(500), recv.recv()).await;
-        let recv_res = match timeout_res {
-            Ok(res) => res,
-            Err(_) => continue,
+    loop {
+        let recv_res = if *stop.lock().await {
+            // drain what has already been processed before exiting
+            match recv.try_recv() {
+                Ok(env) => Ok(env),
+                Err(TryRecvError::Lagged(skipped)) => Err(RecvError::Lagged(skipped)),
+                Err(_) => break,
+            }
+        } else {
+            let timeout_res = tokio::time::timeout(Duration::from_millis(500), recv.recv()).await;
+            match timeout_res {
+                Ok(res) => res,
+                Err(_) => continue,
+            }
         };
         let env = match recv_res {
             Ok(env) => env,
+            // the client was replaced by a restart, which comes with its own logger
+            Err(RecvError::Closed) => break,
             Err(e) => {
                 error!("Failed to receive regular from processing: {e}");
                 continue;
             }
         };
 
-        let recv_tick = ((env.timestamp() - start_time) / tick_millis as u128) as u32;
+        let receive_time = now_millis();
+        let recv_tick = (env.timestamp().saturating_sub(start_time) / tick_millis as u128) as u32;
 
         let msg_size = env.content_bytes().len();
         let source = env.source_account_id();
@@ -270,9 +826,50 @@ async fn recv_logger(
             }
         };
 
+        let (header, integrity) = match PayloadHeader::decode(env.content_bytes()) {
+            Ok((header, integrity)) => {
+                if &header.sender != from_user {
+                    warn!(
+                        "Message from '{from_user}' claims to be sent by '{}'",
+                        header.sender
+                    );
+                }
+                (Some(header), integrity)
+            }
+            Err(e) => {
+                warn!("Failed to decode payload header from '{from_user}': {e}");
+                (None, Integrity::from(&e))
+            }
+        };
+        if integrity != Integrity::Intact {
+            warn!("Received {integrity:?} message from '{from_user}'");
+        }
+
+        let send_time = header.as_ref().map(|header| header.send_time);
+        let sequence = header.as_ref().map(|header| header.sequence);
+        let (id, reply_to, group) = header
+            .map(|header| (Some(header.id()), header.reply_to, header.group))
+            .unwrap_or_default();
+
+        let status = match sequence {
+            Some(sequence) => delivery
+                .lock()
+                .await
+                .received(from_user, &msg_type, sequence),
+            None => Delivery::InOrder,
+        };
+        match status {
+            Delivery::InOrder => {}
+            Delivery::Reordered => {
+                warn!("Message from '{from_user}' arrived out of order")
+            }
+            Delivery::Duplicate => warn!("Message from '{from_user}' arrived twice"),
+        }
+
         let msg = IncomingMessage {
             tick: recv_tick,
             from: from_user.clone(),
+            id: id.clone(),
         };
 
         let reply = match msg_type {
@@ -284,14 +881,24 @@ async fn recv_logger(
             }
         };
 
-        incoming.lock().await.push(reply);
+        if status != Delivery::Duplicate {
+            incoming.lock().await.push(reply);
+        }
         info!("Received message from '{from_user}'");
         msg_log.lock().await.push(MessageLog {
+            id,
+            reply_to,
+            group,
+            sequence,
             r#type: msg_type.clone(),
             from: from_user.clone(),
             to: username.clone(),
             size: msg_size,
             tick: recv_tick,
+            send_time,
+            receive_time: Some(receive_time),
+            latency_millis: send_time.map(|sent| receive_time.saturating_sub(sent)),
+            integrity: Some(integrity),
         });
     }
 }
@@ -299,85 +906,118 @@ async fn recv_logger(
 #[builder]
 async fn send_message(
     username: String,
+    run_start: u128,
+    message_counter: RcMessageCounter,
     client: ArcClient,
     friends: Rc<HashMap<String, Friend>>,
     denim_friends: Rc<HashMap<String, Friend>>,
     account_ids: Rc<HashMap<String, AccountId>>,
     msg_log: ArcLogs,
+    delivery: ArcDelivery,
     denim_prob: f32,
-    message_sizes: (u32, u32),
+    /// Picked from `friends` and `denim_friends` when not given
+    recipient: Option<Recipient>,
+    message_sizes: Arc<MessageSizes>,
+    content: Arc<ContentGenerator>,
     current_tick: u32,
+    jitter: Duration,
+    mut rng: ScenarioRng,
 ) {
-    let (min, max) = message_sizes;
-    let mut rng = thread_rng();
+    tokio::time::sleep(jitter).await;
     let mut guard = client.lock().await;
-    let mut msg_log = msg_log.lock().await;
-
-    let msg = random_bytes(min, max, &mut rng);
-    let denim = sample_prob(denim_prob, &mut rng) && denim_friends.len() > 0;
-
-    let friend = if denim && guard.is_denim() {
-        get_friend(&denim_friends, &mut rng)
-    } else {
-        get_friend(&friends, &mut rng)
+    // the client is gone after a failed restart
+    let Some(guard) = guard.as_mut() else {
+        return;
     };
-    let friend_name = friend.as_ref().and_then(|f| Some(f.username.clone()));
-    let account_id = friend.and_then(|f| account_ids.get(&f.username));
 
-    let (account_id, friend_name) = match (account_id, friend_name) {
-        (Some(f), Some(n)) => (f, n),
-        _ => {
+    let recipient =
+        recipient.or_else(|| Recipient::pick(&friends, &denim_friends, denim_prob, &mut rng));
+    let (account_id, friend_name, msg_type) = match recipient.and_then(|r| {
+        account_ids
+            .get(&r.friend)
+            .map(|id| (id, r.friend, r.msg_type))
+    }) {
+        Some(recipient) => recipient,
+        None => {
             error!("Send Message: Friend does not exist!");
             return;
         }
     };
 
+    let header = PayloadHeader {
+        sender: username.clone(),
+        run_start,
+        counter: message_counter.get(),
+        send_time: now_millis(),
+        reply_to: None,
+        group: None,
+        sequence: delivery.lock().await.next_sequence(&friend_name, &msg_type),
+    };
+    let length = message_sizes.sample(&mut rng);
+    let msg = match random_payload(&header, length, &content, &mut rng) {
+        Ok(msg) => msg,
+        Err(e) => {
+            error!("Failed to create payload: {e}");
+            return;
+        }
+    };
+    message_counter.set(message_counter.get() + 1);
+
     let msg_len = msg.len();
-    let (res, msg_type) = if denim && guard.is_denim() {
-        (
-            guard.enqueue_message(*account_id, msg).await,
-            MessageType::Denim,
-        )
-    } else {
-        (
-            guard.send_message(*account_id, msg).await,
-            MessageType::Regular,
-        )
+    let res = match msg_type {
+        MessageType::Denim => guard.enqueue_message(*account_id, msg).await,
+        _ => guard.send_message(*account_id, msg).await,
     };
 
     if let Err(e) = res {
         error!("Send Message Client Error: {e}");
         return;
     }
+    delivery.lock().await.sent(&friend_name, &msg_type);
     info!("Sent message to '{friend_name}'");
-    msg_log.push(MessageLog {
+    msg_log.lock().await.push(MessageLog {
+        id: Some(header.id()),
+        reply_to: header.reply_to,
+        group: None,
+        sequence: Some(header.sequence),
         r#type: msg_type,
         from: username,
         to: friend_name,
         size: msg_len,
         tick: current_tick,
+        send_time: Some(header.send_time),
+        receive_time: None,
+        latency_millis: None,
+        integrity: None,
     });
 }
 
 #[builder]
 async fn reply_message(
     username: String,
+    run_start: u128,
+    message_counter: RcMessageCounter,
     client: ArcClient,
     friends: Rc<HashMap<String, Friend>>,
     account_ids: Rc<HashMap<String, AccountId>>,
     msg_log: ArcLogs,
-    message_sizes: (u32, u32),
+    delivery: ArcDelivery,
+    message_sizes: Arc<MessageSizes>,
+    content: Arc<ContentGenerator>,
     stale_ticks: u32,
     current_tick: u32,
     reply_prob: f32,
     incoming: ArcIncoming,
+    jitter: Duration,
+    mut rng: ScenarioRng,
 ) {
-    let (min, max) = message_sizes;
-    let mut rng = thread_rng();
+    tokio::time::sleep(jitter).await;
     let mut guard = client.lock().await;
-    let mut msg_log = msg_log.lock().await;
+    // the client is gone after a failed restart
+    let Some(guard) = guard.as_mut() else {
+        return;
+    };
 
-    let msg = random_bytes(min, max, &mut rng);
     let mut messages = incoming.lock().await;
 
     messages.retain(|x| current_tick - x.tick() > stale_ticks);
@@ -416,21 +1056,22 @@ async fn reply_message(
     } else {
         warn!("Reply Message: Could not remove reply");
     }
+    drop(messages);
 
     if !sample_prob(reply_prob, &mut rng) {
         return;
     }
 
-    let (account_id, friend_name, msg_type) = match reply {
+    let (account_id, friend_name, reply_to, msg_type) = match reply {
         ReplyType::Denim(msg) => {
             let friend_name = msg.from;
             let account_id = account_ids.get(&friend_name);
-            (account_id, friend_name, MessageType::Denim)
+            (account_id, friend_name, msg.id, MessageType::Denim)
         }
         ReplyType::Sam(msg) => {
             let friend_name = msg.from;
             let account_id = account_ids.get(&friend_name);
-            (account_id, friend_name, MessageType::Regular)
+            (account_id, friend_name, msg.id, MessageType::Regular)
         }
     };
 
@@ -442,6 +1083,25 @@ async fn reply_message(
         }
     };
 
+    let header = PayloadHeader {
+        sender: username.clone(),
+        run_start,
+        counter: message_counter.get(),
+        send_time: now_millis(),
+        reply_to,
+        group: None,
+        sequence: delivery.lock().await.next_sequence(&friend_name, &msg_type),
+    };
+    let length = message_sizes.sample(&mut rng);
+    let msg = match random_payload(&header, length, &content, &mut rng) {
+        Ok(msg) => msg,
+        Err(e) => {
+            error!("Failed to create payload: {e}");
+            return;
+        }
+    };
+    message_counter.set(message_counter.get() + 1);
+
     let msg_len = msg.len();
     let res = match msg_type {
         MessageType::Denim => guard.enqueue_message(*account_id, msg).await,
@@ -456,12 +1116,151 @@ async fn reply_message(
         error!("Reply Message Client Error: {e}");
         return;
     }
+    delivery.lock().await.sent(&friend_name, &msg_type);
     info!("Sent reply to '{friend_name}'");
-    msg_log.push(MessageLog {
+    msg_log.lock().await.push(MessageLog {
+        id: Some(header.id()),
+        reply_to: header.reply_to,
+        group: None,
+        sequence: Some(header.sequence),
         r#type: msg_type,
         from: username,
         to: friend_name,
         size: msg_len,
         tick: current_tick,
+        send_time: Some(header.send_time),
+        receive_time: None,
+        latency_millis: None,
+        integrity: None,
+    });
+}
+
+#[builder]
+async fn group_message(
+    username: String,
+    run_start: u128,
+    message_counter: RcMessageCounter,
+    client: ArcClient,
+    groups: Rc<HashMap<String, Group>>,
+    account_ids: Rc<HashMap<String, AccountId>>,
+    msg_log: ArcLogs,
+    group_logs: ArcGroups,
+    delivery: ArcDelivery,
+    message_sizes: Arc<MessageSizes>,
+    content: Arc<ContentGenerator>,
+    current_tick: u32,
+    jitter: Duration,
+    mut rng: ScenarioRng,
+) {
+    tokio::time::sleep(jitter).await;
+    let mut guard = client.lock().await;
+    // the client is gone after a failed restart
+    let Some(guard) = guard.as_mut() else {
+        return;
+    };
+
+    let (name, group) = match get_group(&groups, &mut rng) {
+        Some(group) => group,
+        None => {
+            error!("Group Message: Group does not exist!");
+            return;
+        }
+    };
+    let msg_type = if group.denim && guard.is_denim() {
+        MessageType::Denim
+    } else {
+        MessageType::Regular
+    };
+
+    // every member gets the same id and body, only the sequence number differs
+    let template = PayloadHeader {
+        sender: username.clone(),
+        run_start,
+        counter: message_counter.get(),
+        send_time: now_millis(),
+        reply_to: None,
+        group: Some(name.clone()),
+        sequence: 0,
+    };
+    let length = message_sizes.sample(&mut rng) as usize;
+    let body = content.body(length.saturating_sub(template.overhead()), &mut rng);
+    message_counter.set(message_counter.get() + 1);
+
+    let mut members = Vec::new();
+    for member in group.members.iter().filter(|member| **member != username) {
+        let account_id = match account_ids.get(member) {
+            Some(account_id) => account_id,
+            None => {
+                error!("Group Message: Member '{member}' does not exist!");
+                members.push(GroupDelivery {
+                    member: member.clone(),
+                    sequence: None,
+                    sent: false,
+                });
+                continue;
+            }
+        };
+        let header = PayloadHeader {
+            sequence: delivery.lock().await.next_sequence(member, &msg_type),
+            ..template.clone()
+        };
+        // the members reached so far are logged even when a later copy fails
+        let msg = match header.encode(&body) {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Failed to create payload for '{member}': {e}");
+                members.push(GroupDelivery {
+                    member: member.clone(),
+                    sequence: None,
+                    sent: false,
+                });
+                continue;
+            }
+        };
+
+        let msg_len = msg.len();
+        let res = match msg_type {
+            MessageType::Denim => guard.enqueue_message(*account_id, msg).await,
+            _ => guard.send_message(*account_id, msg).await,
+        };
+        let sent = match res {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Group Message Client Error: {e}");
+                false
+            }
+        };
+        if sent {
+            delivery.lock().await.sent(member, &msg_type);
+            msg_log.lock().await.push(MessageLog {
+                id: Some(header.id()),
+                reply_to: None,
+                group: header.group,
+                sequence: Some(header.sequence),
+                r#type: msg_type.clone(),
+                from: username.clone(),
+                to: member.clone(),
+                size: msg_len,
+                tick: current_tick,
+                send_time: Some(header.send_time),
+                receive_time: None,
+                latency_millis: None,
+                integrity: None,
+            });
+        }
+        members.push(GroupDelivery {
+            member: member.clone(),
+            sequence: Some(header.sequence),
+            sent,
+        });
+    }
+
+    info!("Sent group message to '{name}'");
+    group_logs.lock().await.push(GroupMessageLog {
+        id: template.id(),
+        group: name,
+        r#type: msg_type,
+        tick: current_tick,
+        members,
     });
 }
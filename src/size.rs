@@ -0,0 +1,185 @@
+use std::io::BufReader;
+
+use derive_more::{Display, Error, From};
+use rand::{
+    Rng,
+    distributions::{WeightedError, WeightedIndex},
+};
+use rand_distr::{Distribution, LogNormal, Normal, NormalError, Pareto, ParetoError};
+use serde::{Deserialize, Serialize};
+
+use crate::data::SizeDistribution;
+
+#[derive(Debug, Display, Error, From)]
+pub enum SizeError {
+    Io(std::io::Error),
+    Serde(serde_json::Error),
+    Normal(NormalError),
+    Pareto(ParetoError),
+    Weighted(WeightedError),
+}
+
+/// A bin of an empirical size histogram.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SizeBin {
+    pub size: u32,
+    pub weight: f64,
+}
+
+enum Shape {
+    Uniform,
+    Normal(Normal<f64>),
+    LogNormal(LogNormal<f64>),
+    Pareto(Pareto<f64>),
+    Empirical {
+        sizes: Vec<u32>,
+        index: WeightedIndex<f64>,
+    },
+}
+
+/// Draws message sizes from a distribution, clamped to the configured size range.
+pub struct MessageSizes {
+    min: u32,
+    max: u32,
+    shape: Shape,
+}
+
+impl MessageSizes {
+    pub fn new(distribution: &SizeDistribution, range: (u32, u32)) -> Result<Self, SizeError> {
+        let shape = match distribution {
+            SizeDistribution::Uniform => Shape::Uniform,
+            SizeDistribution::Normal { mean, std_dev } => {
+                Shape::Normal(Normal::new(*mean, *std_dev)?)
+            }
+            SizeDistribution::LogNormal { mu, sigma } => {
+                Shape::LogNormal(LogNormal::new(*mu, *sigma)?)
+            }
+            SizeDistribution::Pareto { scale, shape } => {
+                Shape::Pareto(Pareto::new(*scale, *shape)?)
+            }
+            SizeDistribution::Empirical { path } => {
+                let file = std::fs::File::open(path)?;
+                let bins: Vec<SizeBin> = serde_json::from_reader(BufReader::new(file))?;
+                Shape::Empirical {
+                    index: WeightedIndex::new(bins.iter().map(|bin| bin.weight))?,
+                    sizes: bins.into_iter().map(|bin| bin.size).collect(),
+                }
+            }
+        };
+        let (min, max) = range;
+        Ok(Self { min, max, shape })
+    }
+
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> u32 {
+        let size = match &self.shape {
+            Shape::Uniform => return rng.gen_range(self.min..=self.max),
+            Shape::Normal(dist) => dist.sample(rng),
+            Shape::LogNormal(dist) => dist.sample(rng),
+            Shape::Pareto(dist) => dist.sample(rng),
+            Shape::Empirical { sizes, index } => sizes[index.sample(rng)] as f64,
+        };
+        // the float to int cast saturates, so huge samples end up at `max`
+        (size.round() as u32).clamp(self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    fn samples(distribution: SizeDistribution, range: (u32, u32)) -> Vec<u32> {
+        let sizes = MessageSizes::new(&distribution, range).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..10_000).map(|_| sizes.sample(&mut rng)).collect()
+    }
+
+    fn assert_within(samples: &[u32], (min, max): (u32, u32)) {
+        assert!(samples.iter().all(|size| (min..=max).contains(size)));
+    }
+
+    #[test]
+    fn uniform_bounds() {
+        let sizes = samples(SizeDistribution::Uniform, (10, 20));
+        assert_within(&sizes, (10, 20));
+        assert!(sizes.contains(&10));
+        assert!(sizes.contains(&20));
+    }
+
+    #[test]
+    fn normal_bounds() {
+        let distribution = SizeDistribution::Normal {
+            mean: 100.0,
+            std_dev: 50.0,
+        };
+        let sizes = samples(distribution, (80, 120));
+        assert_within(&sizes, (80, 120));
+        // a large share of the samples falls outside the range and is clamped
+        assert!(sizes.contains(&80));
+        assert!(sizes.contains(&120));
+    }
+
+    #[test]
+    fn heavy_tails_are_clamped() {
+        let distribution = SizeDistribution::LogNormal {
+            mu: 5.0,
+            sigma: 3.0,
+        };
+        assert_within(&samples(distribution, (1, 4096)), (1, 4096));
+
+        let distribution = SizeDistribution::Pareto {
+            scale: 100.0,
+            shape: 0.5,
+        };
+        let sizes = samples(distribution, (0, 1000));
+        assert_within(&sizes, (100, 1000));
+        assert!(sizes.contains(&1000));
+    }
+
+    #[test]
+    fn single_size() {
+        let sizes = samples(SizeDistribution::Uniform, (64, 64));
+        assert!(sizes.iter().all(|size| *size == 64));
+    }
+
+    #[test]
+    fn empirical_bins() {
+        let path = std::env::temp_dir().join(format!("size-bins-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"[{"size": 10, "weight": 1.0}, {"size": 500, "weight": 3.0}, {"size": 9000, "weight": 0.0}]"#,
+        )
+        .unwrap();
+        let distribution = SizeDistribution::Empirical {
+            path: path.display().to_string(),
+        };
+        let sizes = samples(distribution, (0, 1000));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(sizes.iter().all(|size| *size == 10 || *size == 500));
+        let small = sizes.iter().filter(|size| **size == 10).count() as f64;
+        assert!((small / sizes.len() as f64 - 0.25).abs() < 0.02);
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        let normal = SizeDistribution::Normal {
+            mean: 100.0,
+            std_dev: f64::NAN,
+        };
+        assert!(matches!(
+            MessageSizes::new(&normal, (0, 10)),
+            Err(SizeError::Normal(_))
+        ));
+        let pareto = SizeDistribution::Pareto {
+            scale: 0.0,
+            shape: 1.0,
+        };
+        assert!(matches!(
+            MessageSizes::new(&pareto, (0, 10)),
+            Err(SizeError::Pareto(_))
+        ));
+    }
+}
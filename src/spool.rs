@@ -0,0 +1,191 @@
+use std::{
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use derive_more::{Display, Error, From};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    data::ClientReport,
+    dispatch::{SamDispatchClient, SamDispatchError},
+};
+
+const UPLOAD_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Display, Error, From)]
+pub enum SpoolError {
+    Io(std::io::Error),
+    Serde(serde_json::Error),
+    Dispatch(SamDispatchError),
+}
+
+/// A report waiting to be uploaded, together with what is needed to upload it from another process.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SpooledReport {
+    pub idempotency_key: String,
+    pub username: String,
+    pub session: Option<String>,
+    pub report: ClientReport,
+}
+
+impl SpooledReport {
+    pub fn new(username: String, session: Option<String>, report: ClientReport) -> Self {
+        Self {
+            idempotency_key: format!("{username}-{}-{}", report.start_time, report.seed),
+            username,
+            session,
+            report,
+        }
+    }
+}
+
+/// Directory holding reports that have not been uploaded yet.
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, SpoolError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn store(&self, report: &SpooledReport) -> Result<PathBuf, SpoolError> {
+        let path = self.dir.join(format!("{}.json", report.idempotency_key));
+        let file = std::fs::File::create(&path)?;
+        serde_json::to_writer(BufWriter::new(file), report)?;
+        Ok(path)
+    }
+
+    pub fn pending(&self) -> Result<Vec<PathBuf>, SpoolError> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    pub fn load(path: &Path) -> Result<SpooledReport, SpoolError> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+}
+
+/// Uploads a spooled report with exponential backoff and removes it from the spool on success.
+pub async fn upload_spooled(
+    dispatch: &SamDispatchClient,
+    report: &SpooledReport,
+    path: &Path,
+) -> Result<(), SpoolError> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match dispatch
+            .upload_results(&report.report, &report.idempotency_key)
+            .await
+        {
+            Ok(()) => break,
+            Err(e) if attempt < UPLOAD_ATTEMPTS => {
+                warn!(
+                    "Failed to upload report for '{}' (attempt {attempt}/{UPLOAD_ATTEMPTS}): {e}",
+                    report.username
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                error!(
+                    "Giving up uploading report for '{}', it is kept in '{}'",
+                    report.username,
+                    path.display()
+                );
+                return Err(e.into());
+            }
+        }
+    }
+    info!("Uploaded report for '{}'", report.username);
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(start_time: u128, seed: u64) -> ClientReport {
+        ClientReport::builder()
+            .start_time(start_time)
+            .seed(seed)
+            .phase_offset_millis(0)
+            .partial(false)
+            .tick_lateness(Vec::new())
+            .messages(Vec::new())
+            .delivery(Vec::new())
+            .sent(Vec::new())
+            .integrity(Vec::new())
+            .conversations(Vec::new())
+            .group_messages(Vec::new())
+            .offline_periods(Vec::new())
+            .reconnects(Vec::new())
+            .faults(Vec::new())
+            .restarts(Vec::new())
+            .build()
+    }
+
+    #[test]
+    fn store_and_load() {
+        let dir = std::env::temp_dir().join(format!("spool-{}", std::process::id()));
+        let spool = Spool::new(&dir).unwrap();
+        let bob = SpooledReport::new("bob".to_string(), None, report(1000, 2));
+        let alice = SpooledReport::new(
+            "alice".to_string(),
+            Some("session".to_string()),
+            report(1000, 1),
+        );
+        spool.store(&bob).unwrap();
+        let path = spool.store(&alice).unwrap();
+        // storing a report again replaces the pending one
+        assert_eq!(spool.store(&alice).unwrap(), path);
+        std::fs::write(dir.join("notes.txt"), "not a report").unwrap();
+
+        let pending = spool.pending().unwrap();
+        let loaded: Vec<SpooledReport> = pending
+            .iter()
+            .map(|path| Spool::load(path).unwrap())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            pending,
+            [dir.join("alice-1000-1.json"), dir.join("bob-1000-2.json")]
+        );
+        assert_eq!(loaded[0].idempotency_key, alice.idempotency_key);
+        assert_eq!(loaded[0].username, "alice");
+        assert_eq!(loaded[0].session.as_deref(), Some("session"));
+        assert_eq!(
+            (loaded[0].report.start_time, loaded[0].report.seed),
+            (1000, 1)
+        );
+        assert_eq!(loaded[1].username, "bob");
+        assert_eq!(loaded[1].session, None);
+    }
+
+    #[test]
+    fn keys_differ_between_runs() {
+        let first = SpooledReport::new("alice".to_string(), None, report(1000, 1));
+        let second = SpooledReport::new("alice".to_string(), None, report(2000, 1));
+        assert_eq!(first.idempotency_key, "alice-1000-1");
+        assert_ne!(first.idempotency_key, second.idempotency_key);
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use derive_more::{Display, Error, From};
+use log::info;
+use sqlx::{ConnectOptions, Connection, sqlite::SqliteConnectOptions};
+
+use crate::config::{StorageConfig, StoreLifecycle};
+
+const STORES: [&str; 2] = ["sam", "denim"];
+/// Files SQLite keeps next to a database, depending on its journal mode.
+const SIDE_FILES: [&str; 4] = ["", "-wal", "-shm", "-journal"];
+/// Pragmas stored in the database file. The clients open the stores with their own
+/// connections, so any other pragma would only apply to the connection `prepare` uses.
+const PERSISTENT_PRAGMAS: [&str; 5] = [
+    "journal_mode",
+    "auto_vacuum",
+    "page_size",
+    "user_version",
+    "application_id",
+];
+
+#[derive(Debug, Display, Error, From)]
+pub enum StoreError {
+    Io(std::io::Error),
+    Sqlite(sqlx::Error),
+    /// Pragma, and its value, that would not outlive the connection it is applied on
+    #[from(ignore)]
+    NotPersistentPragma(#[error(not(source))] String),
+    /// Username that cannot be used in the name of a store file
+    #[from(ignore)]
+    InvalidUsername(#[error(not(source))] String),
+}
+
+/// Rejects usernames that would put store files outside of the store directory.
+pub fn check_username(username: &str) -> Result<(), StoreError> {
+    let invalid = username.is_empty()
+        || username == "."
+        || username == ".."
+        || username.contains(['/', '\\', '\0']);
+    if invalid {
+        return Err(StoreError::InvalidUsername(username.to_string()));
+    }
+    Ok(())
+}
+
+/// Rejects the pragmas that would have no effect on the clients' connections.
+pub fn check_config(config: &StorageConfig) -> Result<(), StoreError> {
+    for (key, value) in &config.pragmas {
+        let key = key.to_lowercase();
+        // only WAL is remembered, other journal modes fall back to the default DELETE
+        let persistent = match key.as_str() {
+            "journal_mode" => ["wal", "delete"].contains(&value.to_lowercase().as_str()),
+            key => PERSISTENT_PRAGMAS.contains(&key),
+        };
+        if !persistent {
+            return Err(StoreError::NotPersistentPragma(format!("{key} = {value}")));
+        }
+    }
+    Ok(())
+}
+
+/// Where a client keeps its SQLite stores and what happens to them after the run.
+#[derive(Clone, Debug)]
+pub struct Stores {
+    dir: PathBuf,
+    username: String,
+    per_client: bool,
+    pragmas: BTreeMap<String, String>,
+    lifecycle: StoreLifecycle,
+}
+
+impl Stores {
+    pub fn new(config: Option<&StorageConfig>, username: &str) -> Self {
+        let root = config
+            .and_then(|config| config.root.as_deref())
+            .map(PathBuf::from)
+            .unwrap_or_default();
+        let per_client = config.is_some_and(|config| config.per_client);
+        Self {
+            dir: if per_client {
+                root.join(username)
+            } else {
+                root
+            },
+            username: username.to_string(),
+            per_client,
+            pragmas: config
+                .map(|config| config.pragmas.clone())
+                .unwrap_or_default(),
+            lifecycle: config
+                .map(|config| config.after_run.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn file(&self, store: &str) -> PathBuf {
+        self.dir.join(format!("{}_{store}.sql", self.username))
+    }
+
+    pub fn url(&self, store: &str) -> String {
+        // sqlx splits the URL at `?` and percent-decodes the path before it
+        let path = self
+            .file(store)
+            .display()
+            .to_string()
+            .replace('%', "%25")
+            .replace('?', "%3F")
+            .replace('#', "%23");
+        format!("sqlite://{path}?mode=rwc")
+    }
+
+    /// The store file and the files SQLite keeps next to it that exist.
+    fn files(&self, store: &str) -> impl Iterator<Item = PathBuf> {
+        let file = self.file(store);
+        SIDE_FILES
+            .iter()
+            .map(move |suffix| PathBuf::from(format!("{}{suffix}", file.display())))
+            .filter(|file| file.exists())
+    }
+
+    /// Whether the store holds a registered account, rather than being missing or left behind
+    /// half-created by a run that stopped before registering. Assumes the SAM client keeps its
+    /// own account in the `Account` table, like its migrations do.
+    pub async fn has_account(&self, store: &str) -> Result<bool, StoreError> {
+        if !self.file(store).exists() {
+            return Ok(false);
+        }
+        let mut conn = SqliteConnectOptions::new()
+            .filename(self.file(store))
+            .read_only(true)
+            .connect()
+            .await?;
+        let migrated: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'Account')",
+        )
+        .fetch_one(&mut conn)
+        .await?;
+        let accounts: i64 = if migrated {
+            sqlx::query_scalar("SELECT COUNT(*) FROM Account")
+                .fetch_one(&mut conn)
+                .await?
+        } else {
+            0
+        };
+        conn.close().await?;
+        Ok(accounts > 0)
+    }
+
+    /// Deletes the store, so the client can register from scratch.
+    pub fn remove(&self, store: &str) -> Result<(), StoreError> {
+        for file in self.files(store) {
+            std::fs::remove_file(file)?;
+        }
+        Ok(())
+    }
+
+    /// Creates the store's directory and applies the configured pragmas to the store file.
+    /// Only pragmas stored in the database file, like `journal_mode`, outlive this connection.
+    pub async fn prepare(&self, store: &str) -> Result<(), StoreError> {
+        if !self.dir.as_os_str().is_empty() {
+            std::fs::create_dir_all(&self.dir)?;
+        }
+        if self.pragmas.is_empty() {
+            return Ok(());
+        }
+        let options = self.pragmas.iter().fold(
+            SqliteConnectOptions::new()
+                .filename(self.file(store))
+                .create_if_missing(true),
+            |options, (key, value)| {
+                options.pragma(Cow::Owned(key.clone()), Cow::Owned(value.clone()))
+            },
+        );
+        options.connect().await?.close().await?;
+        Ok(())
+    }
+
+    /// Keeps, archives or deletes the store files once the client is dropped.
+    pub fn finish(&self, run: u128) -> Result<(), StoreError> {
+        let files: Vec<PathBuf> = STORES.iter().flat_map(|store| self.files(store)).collect();
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        match &self.lifecycle {
+            StoreLifecycle::Keep => return Ok(()),
+            StoreLifecycle::Delete => {
+                for file in &files {
+                    std::fs::remove_file(file)?;
+                }
+                info!("Deleted the stores of '{}'", self.username);
+            }
+            StoreLifecycle::Archive { directory } => {
+                let archive = Path::new(directory).join(format!("{}-{run}", self.username));
+                std::fs::create_dir_all(&archive)?;
+                for file in &files {
+                    if let Some(name) = file.file_name() {
+                        move_file(file, &archive.join(name))?;
+                    }
+                }
+                info!(
+                    "Archived the stores of '{}' in '{}'",
+                    self.username,
+                    archive.display()
+                );
+            }
+        }
+        if self.per_client {
+            // only succeeds if nothing else was put in the client's directory
+            let _ = std::fs::remove_dir(&self.dir);
+        }
+        Ok(())
+    }
+}
+
+/// Renames `from` to `to`, or copies it when the archive is on another filesystem.
+fn move_file(from: &Path, to: &Path) -> Result<(), StoreError> {
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(pragmas: &[(&str, &str)]) -> StorageConfig {
+        StorageConfig {
+            root: None,
+            per_client: false,
+            pragmas: pragmas
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            after_run: StoreLifecycle::Keep,
+        }
+    }
+
+    #[test]
+    fn persistent_pragmas() {
+        let persistent = config(&[
+            ("journal_mode", "WAL"),
+            ("AUTO_VACUUM", "full"),
+            ("page_size", "8192"),
+        ]);
+        assert!(check_config(&persistent).is_ok());
+    }
+
+    #[test]
+    fn connection_pragmas() {
+        for (key, value) in [
+            ("synchronous", "OFF"),
+            ("foreign_keys", "ON"),
+            ("journal_mode", "MEMORY"),
+        ] {
+            assert!(matches!(
+                check_config(&config(&[(key, value)])),
+                Err(StoreError::NotPersistentPragma(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn usernames() {
+        assert!(check_username("alice").is_ok());
+        assert!(check_username("alice.bob").is_ok());
+        for username in ["", ".", "..", "../x", "a/b", "a\\b", "a\0b"] {
+            assert!(
+                matches!(
+                    check_username(username),
+                    Err(StoreError::InvalidUsername(_))
+                ),
+                "{username:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn url_escapes_the_path() {
+        let mut config = config(&[]);
+        config.root = Some("runs/50%?#1".to_string());
+        let stores = Stores::new(Some(&config), "alice");
+        let url = stores.url("sam");
+        assert_eq!(url, "sqlite://runs/50%25%3F%231/alice_sam.sql?mode=rwc");
+
+        let options: SqliteConnectOptions = url.parse().unwrap();
+        assert_eq!(options.get_filename(), stores.file("sam"));
+    }
+
+    #[test]
+    fn per_client_directory() {
+        let mut config = config(&[]);
+        config.root = Some("stores".to_string());
+        config.per_client = true;
+        let stores = Stores::new(Some(&config), "alice");
+        assert_eq!(
+            stores.file("sam"),
+            Path::new("stores").join("alice").join("alice_sam.sql")
+        );
+    }
+
+    #[test]
+    fn archive() {
+        let dir = std::env::temp_dir().join(format!("stores-{}", std::process::id()));
+        let archive = dir.join("archive");
+        let mut config = config(&[]);
+        config.root = Some(dir.display().to_string());
+        config.after_run = StoreLifecycle::Archive {
+            directory: archive.display().to_string(),
+        };
+        let stores = Stores::new(Some(&config), "alice");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(stores.file("sam"), "sam").unwrap();
+        std::fs::write(dir.join("alice_sam.sql-wal"), "wal").unwrap();
+
+        stores.finish(42).unwrap();
+
+        let archived = archive.join("alice-42");
+        assert!(!stores.file("sam").exists());
+        assert_eq!(
+            std::fs::read_to_string(archived.join("alice_sam.sql")).unwrap(),
+            "sam"
+        );
+        assert!(archived.join("alice_sam.sql-wal").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use std::{future::Future, time::Duration};
+
+use log::{error, warn};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    data::{ChannelIntegrity, LogBatch, MessageLog, MessageType},
+    dispatch::SamDispatchClient,
+    utils::integrity_summary,
+};
+
+const BATCH_UPLOAD_ATTEMPTS: u32 = 5;
+
+/// The parts of a received message the end of run summaries need.
+#[derive(Clone, Debug)]
+pub struct ReceivedMessage {
+    pub from: String,
+    pub r#type: MessageType,
+    pub tick: u32,
+    pub send_time: Option<u128>,
+    pub latency_millis: Option<u128>,
+}
+
+/// What is left of message logs once they are handed off, so the logs themselves can be dropped.
+pub struct LogDigest {
+    pub integrity: Vec<ChannelIntegrity>,
+    pub received: Vec<ReceivedMessage>,
+}
+
+impl LogDigest {
+    pub fn new() -> Self {
+        Self {
+            integrity: integrity_summary(&[]),
+            received: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, logs: &[MessageLog]) {
+        // both summaries list the same channels in the same order
+        for (total, part) in self.integrity.iter_mut().zip(integrity_summary(logs)) {
+            total.intact += part.intact;
+            total.truncated.extend(part.truncated);
+            total.corrupted.extend(part.corrupted);
+        }
+        self.received.extend(
+            logs.iter()
+                .filter(|msg| msg.receive_time.is_some())
+                .map(|msg| ReceivedMessage {
+                    from: msg.from.clone(),
+                    r#type: msg.r#type.clone(),
+                    tick: msg.tick,
+                    send_time: msg.send_time,
+                    latency_millis: msg.latency_millis,
+                }),
+        );
+    }
+}
+
+/// Hands message logs to an uploader as numbered batches, keeping only their digest.
+pub struct LogStream {
+    batches: UnboundedSender<LogBatch>,
+    flush_ticks: u32,
+    /// Logs of a batch the uploader did not take, sent again with the next one
+    pending: Vec<MessageLog>,
+    digest: LogDigest,
+    sequence: u64,
+}
+
+impl LogStream {
+    pub fn new(batches: UnboundedSender<LogBatch>, flush_ticks: u32) -> Self {
+        Self {
+            batches,
+            flush_ticks: flush_ticks.max(1),
+            pending: Vec::new(),
+            digest: LogDigest::new(),
+            sequence: 0,
+        }
+    }
+
+    pub fn flush_ticks(&self) -> u32 {
+        self.flush_ticks
+    }
+
+    /// Number of batches flushed so far.
+    pub fn batches(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Sends `logs`, which have been taken out of the client's message log, as the next batch.
+    pub fn flush(&mut self, logs: Vec<MessageLog>) {
+        self.digest.add(&logs);
+        self.pending.extend(logs);
+        if self.pending.is_empty() {
+            return;
+        }
+        let batch = LogBatch {
+            sequence: self.sequence,
+            messages: std::mem::take(&mut self.pending),
+        };
+        if let Err(e) = self.batches.send(batch) {
+            error!(
+                "Log uploader stopped, batch {} was not flushed",
+                self.sequence
+            );
+            self.pending = e.0.messages;
+            return;
+        }
+        self.sequence += 1;
+    }
+
+    pub fn take_digest(&mut self) -> LogDigest {
+        std::mem::replace(&mut self.digest, LogDigest::new())
+    }
+}
+
+/// Uploads batches in order until every `LogStream` sending to `batches` is dropped.
+/// Returns the batches that could not be uploaded, so they can go with the report instead.
+/// Once `deadline` has passed, the remaining batches are returned without trying.
+pub async fn upload_batches(
+    dispatch: SamDispatchClient,
+    mut batches: UnboundedReceiver<LogBatch>,
+    deadline: impl Future<Output = ()>,
+) -> Vec<LogBatch> {
+    tokio::pin!(deadline);
+    let mut expired = false;
+    let mut unsent = Vec::new();
+    while let Some(batch) = batches.recv().await {
+        let uploaded = !expired
+            && tokio::select! {
+                uploaded = upload_batch(&dispatch, &batch) => uploaded,
+                _ = &mut deadline => {
+                    expired = true;
+                    false
+                }
+            };
+        if !uploaded {
+            warn!(
+                "Batch {} is kept to be uploaded with the report",
+                batch.sequence
+            );
+            unsent.push(batch);
+        }
+    }
+    unsent
+}
+
+async fn upload_batch(dispatch: &SamDispatchClient, batch: &LogBatch) -> bool {
+    for attempt in 1..=BATCH_UPLOAD_ATTEMPTS {
+        match dispatch.upload_batch(batch).await {
+            Ok(()) => return true,
+            Err(e) => {
+                error!(
+                    "Failed to upload batch {} (attempt {attempt}/{BATCH_UPLOAD_ATTEMPTS}): {e}",
+                    batch.sequence
+                );
+                if attempt < BATCH_UPLOAD_ATTEMPTS {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+    false
+}
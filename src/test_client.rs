@@ -9,6 +9,7 @@ use denim_sam_common::{
     buffers::{InMemoryReceivingBuffer, InMemorySendingBuffer},
 };
 use derive_more::{Display, Error, From};
+use log::info;
 use rustls::ClientConfig;
 use sam_client::{
     Client, ClientError,
@@ -18,14 +19,57 @@ use sam_client::{
     storage::{SqliteStoreConfig, error::DatabaseError, sqlite::sqlite_connector::SqliteConnector},
 };
 use sam_common::AccountId;
+use sam_net::error::WebSocketError;
+use std::io::ErrorKind;
 use tokio::sync::broadcast::Receiver;
 
+use crate::storage::{StoreError, Stores};
+
 #[derive(Debug, Display, Error, From)]
 pub enum TestClientError {
     Sam(ClientError),
     Denim(DenimClientError),
 }
 
+impl TestClientError {
+    /// Whether the error comes from a connection to the server that was closed or reset,
+    /// which reconnecting can fix: the websocket reported the server as disconnected, or a
+    /// socket error in the source chain says the connection is gone.
+    pub fn is_connection_lost(&self) -> bool {
+        let (disconnected, mut source): (bool, Option<&(dyn std::error::Error + 'static)>) =
+            match self {
+                TestClientError::Sam(e) => (
+                    matches!(e, ClientError::WebSocket(WebSocketError::Disconnected)),
+                    Some(e),
+                ),
+                TestClientError::Denim(e) => (
+                    matches!(e, DenimClientError::WebSocket(WebSocketError::Disconnected)),
+                    Some(e),
+                ),
+            };
+        if disconnected {
+            return true;
+        }
+        while let Some(error) = source {
+            let io_lost = error.downcast_ref::<std::io::Error>().is_some_and(|e| {
+                matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                        | ErrorKind::BrokenPipe
+                        | ErrorKind::NotConnected
+                        | ErrorKind::UnexpectedEof
+                )
+            });
+            if io_lost {
+                return true;
+            }
+            source = error.source();
+        }
+        false
+    }
+}
+
 #[derive(Debug, Display, Error, From)]
 pub enum TestClientCreationError {
     Sam(SamClientCreationError),
@@ -35,12 +79,16 @@ pub enum TestClientCreationError {
 #[derive(Debug, Display, Error, From)]
 pub enum SamClientCreationError {
     Database(DatabaseError),
+    Store(StoreError),
     Client(ClientError),
 }
 
 #[derive(Debug, Display, Error, From)]
 pub enum DenimClientCreationError {
     Database(DatabaseError),
+    Store(StoreError),
+    /// Username whose SAM or DenIM store is missing while the other one exists
+    IncompleteStores(#[error(not(source))] String),
     Buffer(DenimBufferError),
     Client(DenimClientError),
 }
@@ -60,11 +108,26 @@ impl TestClient {
         tls: Option<ClientConfig>,
         upload_count: usize,
         inmemory: bool,
+        stores: Stores,
     ) -> Result<Self, TestClientCreationError> {
+        let existing = !inmemory
+            && stores
+                .has_account("sam")
+                .await
+                .map_err(SamClientCreationError::Store)?;
+        if !inmemory && !existing {
+            stores
+                .remove("sam")
+                .map_err(SamClientCreationError::Store)?;
+        }
         let store_url = if inmemory {
             "sqlite::memory:".to_string()
         } else {
-            format!("sqlite://{}_sam.sql?mode=rwc", username)
+            stores
+                .prepare("sam")
+                .await
+                .map_err(SamClientCreationError::Store)?;
+            stores.url("sam")
         };
         let sam_conn = SqliteConnector::migrate(&store_url)
             .await
@@ -83,7 +146,18 @@ impl TestClient {
             ),
         };
 
-        Ok(Self::Sam(
+        let client = if existing {
+            info!(
+                "Reusing the account stored in '{}'",
+                stores.file("sam").display()
+            );
+            Client::from_store()
+                .store_config(store)
+                .api_client_config(http)
+                .protocol_config(ws)
+                .call()
+                .await
+        } else {
             Client::from_registration()
                 .username(&username)
                 .device_name(&format!("{}#device", username))
@@ -93,8 +167,8 @@ impl TestClient {
                 .upload_prekey_count(upload_count)
                 .call()
                 .await
-                .map_err(SamClientCreationError::Client)?,
-        ))
+        };
+        Ok(Self::Sam(client.map_err(SamClientCreationError::Client)?))
     }
 
     #[builder]
@@ -105,14 +179,38 @@ impl TestClient {
         tls: Option<ClientConfig>,
         upload_count: usize,
         inmemory: bool,
+        stores: Stores,
     ) -> Result<Self, TestClientCreationError> {
+        let existing = if inmemory {
+            false
+        } else {
+            // both stores are needed to restore a DenIM client
+            if stores.file("sam").exists() != stores.file("denim").exists() {
+                return Err(DenimClientCreationError::IncompleteStores(username).into());
+            }
+            let existing = stores
+                .has_account("sam")
+                .await
+                .map_err(DenimClientCreationError::Store)?;
+            if !existing {
+                for store in ["sam", "denim"] {
+                    stores
+                        .remove(store)
+                        .map_err(DenimClientCreationError::Store)?;
+                }
+            }
+            existing
+        };
         let (store_url, denim_store_url) = if inmemory {
             ("sqlite::memory:".to_string(), "sqlite::memory:".to_string())
         } else {
-            (
-                format!("sqlite://{}_sam.sql?mode=rwc", username),
-                format!("sqlite://{}_denim.sql?mode=rwc", username),
-            )
+            for store in ["sam", "denim"] {
+                stores
+                    .prepare(store)
+                    .await
+                    .map_err(DenimClientCreationError::Store)?;
+            }
+            (stores.url("sam"), stores.url("denim"))
         };
 
         let sam_conn = SqliteConnector::migrate(&store_url)
@@ -139,7 +237,21 @@ impl TestClient {
             ),
         };
 
-        Ok(Self::Denim(
+        let client = if existing {
+            info!(
+                "Reusing the account stored in '{}' and '{}'",
+                stores.file("sam").display(),
+                stores.file("denim").display()
+            );
+            DenimClient::from_store()
+                .store_config(store)
+                .deniable_store_config(denim_store)
+                .api_client_config(http)
+                .protocol_config(ws)
+                .message_queue_config(InMemoryMessageQueueConfig::default())
+                .call()
+                .await
+        } else {
             DenimClient::from_registration()
                 .username(&username)
                 .device_name(&format!("{}#device", username))
@@ -151,7 +263,9 @@ impl TestClient {
                 .upload_prekey_count(upload_count)
                 .call()
                 .await
-                .map_err(DenimClientCreationError::Client)?,
+        };
+        Ok(Self::Denim(
+            client.map_err(DenimClientCreationError::Client)?,
         ))
     }
 
@@ -210,6 +324,14 @@ impl TestClient {
         })
     }
 
+    pub async fn reconnect(&mut self) -> Result<(), TestClientError> {
+        match self {
+            TestClient::Sam(client) => client.reconnect().await?,
+            TestClient::Denim(denim_client) => denim_client.reconnect().await?,
+        }
+        Ok(())
+    }
+
     pub async fn disconnect(&mut self) -> Result<(), TestClientError> {
         Ok(match self {
             TestClient::Sam(client) => client.disconnect().await?,
@@ -217,3 +339,44 @@ impl TestClient {
         })
     }
 }
+
+/// What a client is created from, kept to recreate it from its stores after a restart.
+#[derive(Clone)]
+pub struct ClientSettings {
+    pub denim: bool,
+    pub address: String,
+    pub username: String,
+    pub buffer_size: usize,
+    pub tls: Option<ClientConfig>,
+    pub upload_count: usize,
+    pub inmemory: bool,
+    pub stores: Stores,
+}
+
+impl ClientSettings {
+    pub async fn create(&self) -> Result<TestClient, TestClientCreationError> {
+        if self.denim {
+            TestClient::new_denim()
+                .address(self.address.clone())
+                .buffer_size(self.buffer_size)
+                .maybe_tls(self.tls.clone())
+                .username(self.username.clone())
+                .upload_count(self.upload_count)
+                .inmemory(self.inmemory)
+                .stores(self.stores.clone())
+                .call()
+                .await
+        } else {
+            TestClient::new_sam()
+                .address(self.address.clone())
+                .buffer_size(self.buffer_size)
+                .maybe_tls(self.tls.clone())
+                .username(self.username.clone())
+                .upload_count(self.upload_count)
+                .inmemory(self.inmemory)
+                .stores(self.stores.clone())
+                .call()
+                .await
+        }
+    }
+}
@@ -1,24 +1,58 @@
 use std::time::Duration;
 
+use tokio::time::{Instant, Interval, MissedTickBehavior, interval_at};
+
+use crate::data::{MissedTickPolicy, TickLateness};
+
+/// Ticks anchored to the time the timer was created, so time spent on work does not make
+/// the schedule drift.
 pub struct Timer {
+    interval: Interval,
+    start: Instant,
     tick_duration: Duration,
+    policy: MissedTickPolicy,
     end_tick: u32,
     counter: u32,
+    lateness: Vec<TickLateness>,
 }
 
 impl Timer {
-    pub fn new(tick_duration: Duration, end_tick: u32) -> Self {
+    pub fn new(tick_duration: Duration, end_tick: u32, policy: MissedTickPolicy) -> Self {
+        let tick_duration = tick_duration.max(Duration::from_millis(1));
+        let start = Instant::now();
+        let mut interval = interval_at(start + tick_duration, tick_duration);
+        interval.set_missed_tick_behavior(match policy {
+            MissedTickPolicy::Burst => MissedTickBehavior::Burst,
+            MissedTickPolicy::Skip => MissedTickBehavior::Skip,
+            MissedTickPolicy::Delay => MissedTickBehavior::Delay,
+        });
         Self {
+            interval,
+            start,
             tick_duration,
+            policy,
             end_tick,
             counter: 0,
+            lateness: Vec::new(),
         }
     }
 
     pub async fn next(&mut self) -> bool {
-        self.counter += 1;
-        tokio::time::sleep(self.tick_duration).await;
-        self.counter != self.end_tick
+        let deadline = self.interval.tick().await;
+        let lateness = Instant::now().saturating_duration_since(deadline);
+        self.counter = match self.policy {
+            // skipped ticks are not executed, so the counter follows the schedule
+            MissedTickPolicy::Skip => {
+                (deadline.duration_since(self.start).as_nanos() / self.tick_duration.as_nanos())
+                    as u32
+            }
+            MissedTickPolicy::Burst | MissedTickPolicy::Delay => self.counter + 1,
+        };
+        self.lateness.push(TickLateness {
+            tick: self.counter,
+            lateness_millis: lateness.as_millis() as u64,
+        });
+        self.counter < self.end_tick
     }
 
     pub fn do_action(&self, rate: u32) -> bool {
@@ -28,4 +62,99 @@ impl Timer {
     pub fn current_tick(&self) -> u32 {
         self.counter
     }
+
+    pub fn take_lateness(&mut self) -> Vec<TickLateness> {
+        std::mem::take(&mut self.lateness)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    fn lateness(timer: &mut Timer) -> Vec<(u32, u64)> {
+        timer
+            .take_lateness()
+            .into_iter()
+            .map(|tick| (tick.tick, tick.lateness_millis))
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_catches_up_on_every_tick() {
+        let mut timer = Timer::new(TICK, 10, MissedTickPolicy::Burst);
+        tokio::time::advance(TICK * 7 / 2).await;
+        for _ in 0..4 {
+            assert!(timer.next().await);
+        }
+        assert_eq!(timer.current_tick(), 4);
+        assert_eq!(lateness(&mut timer), [(1, 25), (2, 15), (3, 5), (4, 0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_follows_the_schedule() {
+        let mut timer = Timer::new(TICK, 10, MissedTickPolicy::Skip);
+        tokio::time::advance(TICK * 7 / 2).await;
+        assert!(timer.next().await);
+        assert_eq!(timer.current_tick(), 1);
+        // ticks 2 and 3 are skipped, the counter is rebuilt from the deadline
+        assert!(timer.next().await);
+        assert_eq!(timer.current_tick(), 4);
+        assert!(timer.next().await);
+        assert_eq!(timer.current_tick(), 5);
+        assert_eq!(lateness(&mut timer), [(1, 25), (4, 0), (5, 0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delay_shifts_the_schedule() {
+        let mut timer = Timer::new(TICK, 10, MissedTickPolicy::Delay);
+        tokio::time::advance(TICK * 7 / 2).await;
+        let start = Instant::now();
+        assert!(timer.next().await);
+        assert!(timer.next().await);
+        assert!(timer.next().await);
+        assert_eq!(timer.current_tick(), 3);
+        // the ticks after the late one follow it a full tick apart
+        assert_eq!(Instant::now() - start, TICK * 2);
+        assert_eq!(lateness(&mut timer), [(1, 25), (2, 0), (3, 0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ends_at_end_tick() {
+        for policy in [
+            MissedTickPolicy::Burst,
+            MissedTickPolicy::Skip,
+            MissedTickPolicy::Delay,
+        ] {
+            let mut timer = Timer::new(TICK, 3, policy);
+            assert!(timer.next().await);
+            assert!(timer.next().await);
+            assert!(!timer.next().await);
+            assert_eq!(timer.current_tick(), 3);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_past_end_tick_ends_the_run() {
+        let mut timer = Timer::new(TICK, 3, MissedTickPolicy::Skip);
+        assert!(timer.next().await);
+        tokio::time::advance(TICK * 9 / 2).await;
+        // the late tick 2 still runs, the next deadline is already past the end
+        assert!(timer.next().await);
+        assert!(!timer.next().await);
+        assert_eq!(timer.current_tick(), 6);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn actions_follow_the_counter() {
+        let mut timer = Timer::new(TICK, 10, MissedTickPolicy::Burst);
+        let mut actions = Vec::new();
+        for _ in 0..6 {
+            timer.next().await;
+            actions.push(timer.do_action(3));
+        }
+        assert_eq!(actions, [false, false, true, false, false, true]);
+    }
 }
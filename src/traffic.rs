@@ -0,0 +1,269 @@
+use std::f64::consts::TAU;
+
+use rand::Rng;
+use rand_distr::{Distribution, Poisson};
+
+use crate::{
+    data::{LoadShape, ProfileSegment, SendProcess},
+    utils::sample_prob,
+};
+
+/// Traffic load over the run, outside of any segment the load is 1.0.
+pub struct TrafficProfile {
+    segments: Vec<ProfileSegment>,
+}
+
+impl TrafficProfile {
+    pub fn new(segments: Vec<ProfileSegment>) -> Self {
+        Self { segments }
+    }
+
+    fn segment(&self, tick: u32) -> Option<&ProfileSegment> {
+        self.segments
+            .iter()
+            .find(|segment| segment.start_tick <= tick && tick < segment.end_tick)
+    }
+
+    pub fn load(&self, tick: u32) -> f64 {
+        self.segment(tick)
+            .map(|segment| {
+                let offset = (tick - segment.start_tick) as f64;
+                let length = (segment.end_tick - segment.start_tick) as f64;
+                match segment.load {
+                    LoadShape::Constant { load } => load,
+                    LoadShape::Ramp { from, to } => from + (to - from) * offset / length,
+                    LoadShape::Sine {
+                        min,
+                        max,
+                        period_ticks,
+                    } => {
+                        let phase = TAU * offset / period_ticks.max(1) as f64;
+                        min + (max - min) * (1.0 - phase.cos()) / 2.0
+                    }
+                }
+            })
+            .unwrap_or(1.0)
+            .max(0.0)
+    }
+
+    pub fn denim_probability(&self, tick: u32) -> Option<f32> {
+        self.segment(tick)
+            .and_then(|segment| segment.denim_probability)
+    }
+}
+
+/// Paces an action configured to happen every `rate` ticks, scaled by the load of each tick.
+/// Every tick adds its load, and each time `rate` has been accumulated an action is due,
+/// so a changing load is followed smoothly and a load above `rate` runs several per tick.
+pub struct RateAccumulator {
+    rate: u32,
+    accumulated: f64,
+}
+
+impl RateAccumulator {
+    /// A `rate` of 0 disables the action.
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            accumulated: 0.0,
+        }
+    }
+
+    /// Like `new`, but the first action is due in the first tick at full load.
+    pub fn due_first(rate: u32) -> Self {
+        Self {
+            rate,
+            accumulated: rate.saturating_sub(1) as f64,
+        }
+    }
+
+    /// Number of actions due in a tick with the given load.
+    pub fn due(&mut self, load: f64) -> u32 {
+        if self.rate == 0 {
+            return 0;
+        }
+        let rate = self.rate as f64;
+        self.accumulated += load.max(0.0);
+        let due = (self.accumulated / rate).floor();
+        self.accumulated -= due * rate;
+        due as u32
+    }
+}
+
+/// Decides how many messages a client sends in each tick.
+pub struct SendScheduler {
+    process: SendProcess,
+    fixed: RateAccumulator,
+    on: bool,
+}
+
+impl SendScheduler {
+    pub fn new(process: SendProcess, send_rate: u32) -> Self {
+        Self {
+            process,
+            // a fixed interval has always sent at ticks 0, `send_rate`, 2 * `send_rate`, ...
+            fixed: RateAccumulator::due_first(send_rate),
+            on: true,
+        }
+    }
+
+    pub fn sends<R: Rng>(&mut self, load: f64, rng: &mut R) -> u32 {
+        match self.process {
+            SendProcess::Fixed => self.fixed.due(load),
+            SendProcess::Poisson { rate } => poisson(rate * load, rng),
+            SendProcess::OnOff {
+                rate,
+                mean_on_ticks,
+                mean_off_ticks,
+            } => {
+                let (sends, mean_ticks) = if self.on {
+                    (poisson(rate * load, rng), mean_on_ticks)
+                } else {
+                    (0, mean_off_ticks)
+                };
+                // leaving a period with probability 1/mean gives geometric period lengths
+                if sample_prob((1.0 / mean_ticks.max(1.0)) as f32, rng) {
+                    self.on = !self.on;
+                }
+                sends
+            }
+        }
+    }
+}
+
+fn poisson<R: Rng>(rate: f64, rng: &mut R) -> u32 {
+    Poisson::new(rate)
+        .map(|dist| dist.sample(rng) as u32)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    fn total<F: FnMut() -> u32>(ticks: u32, mut due: F) -> u32 {
+        (0..ticks).map(|_| due()).sum()
+    }
+
+    #[test]
+    fn accumulator_at_full_load() {
+        let mut accumulator = RateAccumulator::new(4);
+        let due: Vec<u32> = (0..8).map(|_| accumulator.due(1.0)).collect();
+        assert_eq!(due, vec![0, 0, 0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn accumulator_scales_with_load() {
+        let mut accumulator = RateAccumulator::new(4);
+        assert_eq!(total(100, || accumulator.due(0.5)), 12);
+
+        let mut accumulator = RateAccumulator::new(4);
+        assert_eq!(total(100, || accumulator.due(2.0)), 50);
+    }
+
+    #[test]
+    fn accumulator_runs_several_per_tick() {
+        let mut accumulator = RateAccumulator::new(1);
+        assert_eq!(accumulator.due(3.0), 3);
+        assert_eq!(accumulator.due(0.5), 0);
+        assert_eq!(accumulator.due(0.5), 1);
+    }
+
+    #[test]
+    fn accumulator_keeps_the_remainder_across_load_changes() {
+        let mut accumulator = RateAccumulator::new(2);
+        assert_eq!(accumulator.due(1.5), 0);
+        assert_eq!(accumulator.due(0.0), 0);
+        assert_eq!(accumulator.due(0.5), 1);
+    }
+
+    #[test]
+    fn accumulator_disabled_or_idle() {
+        let mut disabled = RateAccumulator::new(0);
+        assert_eq!(total(100, || disabled.due(5.0)), 0);
+
+        let mut negative = RateAccumulator::new(1);
+        assert_eq!(total(100, || negative.due(-1.0)), 0);
+        // negative loads are not owed back
+        assert_eq!(negative.due(1.0), 1);
+    }
+
+    #[test]
+    fn fixed_scheduler() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut scheduler = SendScheduler::new(SendProcess::Fixed, 5);
+        assert_eq!(total(1000, || scheduler.sends(1.0, &mut rng)), 200);
+    }
+
+    #[test]
+    fn fixed_scheduler_keeps_the_baseline_ticks() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut scheduler = SendScheduler::new(SendProcess::Fixed, 5);
+        let ticks: Vec<u32> = (0..21)
+            .filter(|_| scheduler.sends(1.0, &mut rng) == 1)
+            .collect();
+        assert_eq!(ticks, vec![0, 5, 10, 15, 20]);
+
+        let mut scheduler = SendScheduler::new(SendProcess::Fixed, 1);
+        assert_eq!(total(3, || scheduler.sends(1.0, &mut rng)), 3);
+        let mut disabled = SendScheduler::new(SendProcess::Fixed, 0);
+        assert_eq!(total(10, || disabled.sends(1.0, &mut rng)), 0);
+    }
+
+    #[test]
+    fn poisson_scheduler_mean() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut scheduler = SendScheduler::new(SendProcess::Poisson { rate: 2.0 }, 1);
+        let mean = total(10_000, || scheduler.sends(0.5, &mut rng)) as f64 / 10_000.0;
+        assert!((mean - 1.0).abs() < 0.05, "mean {mean}");
+
+        assert_eq!(total(100, || scheduler.sends(0.0, &mut rng)), 0);
+    }
+
+    #[test]
+    fn on_off_scheduler_mean() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let process = SendProcess::OnOff {
+            rate: 2.0,
+            mean_on_ticks: 10.0,
+            mean_off_ticks: 30.0,
+        };
+        let mut scheduler = SendScheduler::new(process, 1);
+        // on a quarter of the time
+        let mean = total(100_000, || scheduler.sends(1.0, &mut rng)) as f64 / 100_000.0;
+        assert!((mean - 0.5).abs() < 0.05, "mean {mean}");
+    }
+
+    #[test]
+    fn profile_load() {
+        let segment = |start_tick, end_tick, load| ProfileSegment {
+            start_tick,
+            end_tick,
+            load,
+            denim_probability: None,
+        };
+        let profile = TrafficProfile::new(vec![
+            segment(10, 20, LoadShape::Constant { load: 3.0 }),
+            segment(20, 30, LoadShape::Ramp { from: 0.0, to: 1.0 }),
+            segment(
+                30,
+                40,
+                LoadShape::Sine {
+                    min: 1.0,
+                    max: 3.0,
+                    period_ticks: 4,
+                },
+            ),
+            segment(40, 50, LoadShape::Constant { load: -1.0 }),
+        ]);
+        assert_eq!(profile.load(0), 1.0);
+        assert_eq!(profile.load(10), 3.0);
+        assert_eq!(profile.load(25), 0.5);
+        assert_eq!(profile.load(30), 1.0);
+        assert!((profile.load(32) - 3.0).abs() < 1e-9);
+        assert_eq!(profile.load(45), 0.0);
+        assert_eq!(profile.load(50), 1.0);
+    }
+}
@@ -1,10 +1,17 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use log::error;
 use rand::{Rng, distributions::WeightedIndex, prelude::Distribution};
 use sam_common::AccountId;
 
-use crate::data::Friend;
+use crate::{
+    content::ContentGenerator,
+    data::{ChannelIntegrity, Friend, Group, IntegrityFailure, MessageLog, MessageType},
+    payload::{Integrity, PayloadError, PayloadHeader},
+};
 
 pub fn normal_friends(friends: &HashMap<String, Friend>) -> HashMap<String, Friend> {
     friends
@@ -30,7 +37,9 @@ pub fn usernames(account_ids: &HashMap<String, AccountId>) -> HashMap<AccountId,
 }
 
 pub fn get_friend<R: Rng>(friends: &HashMap<String, Friend>, rng: &mut R) -> Option<Friend> {
-    let values: Vec<&Friend> = friends.values().collect();
+    // HashMap iteration order differs between runs, sort so seeded runs pick the same friend
+    let mut values: Vec<&Friend> = friends.values().collect();
+    values.sort_by(|a, b| a.username.cmp(&b.username));
     let weights: Vec<f64> = values.iter().map(|f| f.frequency).collect();
 
     WeightedIndex::new(&weights)
@@ -42,9 +51,74 @@ pub fn get_friend<R: Rng>(friends: &HashMap<String, Friend>, rng: &mut R) -> Opt
         })
 }
 
-pub fn random_bytes<R: Rng>(min: u32, max: u32, rng: &mut R) -> Vec<u8> {
-    let length = rng.gen_range(min..=max);
-    (0..length).map(|_| rng.r#gen()).collect()
+pub fn get_group<R: Rng>(groups: &HashMap<String, Group>, rng: &mut R) -> Option<(String, Group)> {
+    let mut values: Vec<(&String, &Group)> = groups.iter().collect();
+    values.sort_by(|a, b| a.0.cmp(b.0));
+    let weights: Vec<f64> = values.iter().map(|(_, g)| g.frequency).collect();
+
+    WeightedIndex::new(&weights)
+        .inspect_err(|e| error!("{e}"))
+        .ok()
+        .map(|dist| {
+            let (name, group) = values[dist.sample(rng)];
+            (name.clone(), group.clone())
+        })
+}
+
+/// Creates a payload of `length` bytes starting with the encoded `header`, followed by a body from `content`.
+/// The payload is only larger than `length` if the header itself does not fit.
+pub fn random_payload<R: Rng>(
+    header: &PayloadHeader,
+    length: u32,
+    content: &ContentGenerator,
+    rng: &mut R,
+) -> Result<Vec<u8>, PayloadError> {
+    let body_length = (length as usize).saturating_sub(header.overhead());
+    header.encode(&content.body(body_length, rng))
+}
+
+/// Summarises the integrity of received messages for the regular and deniable channel.
+pub fn integrity_summary(messages: &[MessageLog]) -> Vec<ChannelIntegrity> {
+    [MessageType::Regular, MessageType::Denim]
+        .into_iter()
+        .map(|channel| {
+            let mut summary = ChannelIntegrity {
+                r#type: channel.clone(),
+                intact: 0,
+                truncated: Vec::new(),
+                corrupted: Vec::new(),
+            };
+            for msg in messages.iter().filter(|msg| msg.r#type == channel) {
+                let failure = || IntegrityFailure {
+                    id: msg.id.clone(),
+                    from: msg.from.clone(),
+                    tick: msg.tick,
+                };
+                match msg.integrity {
+                    Some(Integrity::Intact) => summary.intact += 1,
+                    Some(Integrity::Truncated) => summary.truncated.push(failure()),
+                    Some(Integrity::Corrupted) => summary.corrupted.push(failure()),
+                    None => {}
+                }
+            }
+            summary
+        })
+        .collect()
+}
+
+pub fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time cannot go backwards")
+        .as_millis()
+}
+
+/// Uniform delay in `0..=max_millis`, does not touch `rng` without a maximum.
+pub fn random_delay<R: Rng>(max_millis: Option<u32>, rng: &mut R) -> Duration {
+    match max_millis {
+        Some(max) if max > 0 => Duration::from_millis(rng.gen_range(0..=max).into()),
+        _ => Duration::ZERO,
+    }
 }
 
 pub fn sample_prob<R: Rng>(prob: f32, rng: &mut R) -> bool {